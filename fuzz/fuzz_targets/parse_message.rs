@@ -0,0 +1,11 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate pdnsd;
+
+use pdnsd::dns::Message;
+
+// anything a client sends us has to come back as an error, not a panic
+fuzz_target!(|data: &[u8]| {
+    let _ = Message::new(data);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate pdnsd;
+
+use pdnsd::dns::Message;
+
+// whatever we manage to parse and encode again has to parse back into a
+// message that encodes the same way
+fuzz_target!(|data: &[u8]| {
+    let bytes = match Message::new(data).map(|m| m.to_bytes()) {
+        Ok(Ok(bytes)) => bytes,
+        _ => return
+    };
+
+    let msg = Message::new(&bytes).expect("re-encoded message doesn't parse");
+    assert_eq!(bytes, msg.to_bytes().expect("re-encoded message doesn't encode"));
+});
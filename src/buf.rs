@@ -14,7 +14,8 @@ use std::io::Write;
 /// This `Buf` is better suited for cases where there is a clear delineation
 /// between reading and writing.
 
-const BUF_LEN: usize = 512;
+/// Big enough for any UDP payload size we advertise with EDNS.
+pub const BUF_LEN: usize = 4096;
 
 #[derive (Debug, PartialEq, Clone, Copy)]
 enum Mode {
@@ -1,39 +1,1216 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, BTreeMap};
+use std::cmp;
+use std::mem;
+use std::io::{Cursor, Write};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use dns::*;
+use errors::Error;
 use time;
 
+/// Bounds applied to the TTLs handed to us by upstream servers, how long
+/// expired entries are kept around to be served when upstreams fail and
+/// how much the cache may hold before least recently used entries are evicted.
+#[derive (Debug, Clone, Copy)]
+pub struct Options {
+    pub min_ttl: i32,
+    pub max_ttl: i32,
+    pub max_negative_ttl: i32,
+    pub stale_window: i32,
+    pub prefetch_hits: u32,
+    pub max_entries: usize,
+    pub max_bytes: usize
+}
+
+impl Options {
+    pub fn default() -> Options {
+        Options {
+            min_ttl: 0,
+            max_ttl: 24 * 60 * 60,
+            max_negative_ttl: 3 * 60 * 60,
+            stale_window: 24 * 60 * 60,
+            prefetch_hits: 5,
+            max_entries: 10000,
+            max_bytes: 16 * 1024 * 1024
+        }
+    }
+}
+
+#[derive (Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub stale_hits: u64,
+    pub prefetches: u64,
+    pub insertions: u64,
+    pub expirations: u64,
+    pub evictions: u64
+}
+
+const RCODE_NXDOMAIN: u16 = 3;
+/// Longest CNAME chain followed through a response.
+const MAX_CNAME_CHAIN: usize = 8;
+/// TTL handed out with stale answers, as recommended by RFC 8767.
+const STALE_TTL: i32 = 30;
+/// Seconds before an entry whose prefetch never came back is handed out
+/// for prefetching again, the query may have failed or never started.
+const PREFETCH_RETRY_SECS: f64 = 15.0;
+
+const FILE_MAGIC: &'static [u8] = b"PDNS";
+const FILE_VERSION: u16 = 1;
+const TYPE_CNAME: u16 = 0x05;
+const TYPE_SOA: u16 = 0x06;
+const TYPE_ANY: u16 = 0xff;
+/// Stands in for the type of NXDOMAIN entries, they deny every type the
+/// name could have (RFC 2308 section 5).
+pub const TYPE_NAME: u16 = 0;
+
+/// What the cache knows about a question.
+#[derive (Debug, Clone)]
+pub enum Answer {
+    /// The RRset asked for.
+    Records(Vec<ResourceRecord>),
+    /// The name (NXDOMAIN) or the type (NODATA) doesn't exist, along with
+    /// the CNAMEs the denied name was reached through, if any, and the SOA
+    /// record the denial came with (RFC 2308).
+    Negative(u16, Vec<ResourceRecord>, ResourceRecord)
+}
+
+impl Answer {
+    fn with_ttl(&self, ttl: i32) -> Answer {
+        let set_ttl = |r: &ResourceRecord| {
+            let mut record = r.clone();
+            record.r_ttl = ttl;
+            record
+        };
+
+        match *self {
+            Answer::Records(ref records) => Answer::Records(records.iter().map(set_ttl).collect()),
+            Answer::Negative(rcode, ref cnames, ref soa) => {
+                Answer::Negative(rcode, cnames.iter().map(set_ttl).collect(), set_ttl(soa))
+            }
+        }
+    }
+
+    /// The answer as reached through the CNAME records in `cnames`.
+    fn through(self, mut cnames: Vec<ResourceRecord>) -> Answer {
+        match self {
+            Answer::Records(records) => {
+                cnames.extend(records);
+                Answer::Records(cnames)
+            },
+            Answer::Negative(rcode, _, soa) => Answer::Negative(rcode, cnames, soa)
+        }
+    }
+
+    /// A rough figure of the memory held by the answer.
+    fn size(&self) -> usize {
+        let record_size = |r: &ResourceRecord| {
+            mem::size_of::<ResourceRecord>() + r.r_data.heap_size()
+        };
+
+        match *self {
+            Answer::Records(ref records) => records.iter().map(record_size).fold(0, |a, b| a + b),
+            Answer::Negative(_, ref cnames, ref soa) => {
+                cnames.iter().map(record_size).fold(record_size(soa), |a, b| a + b)
+            }
+        }
+    }
+}
+
+/// Cached RRsets are looked up by owner name, type and class, the name is
+/// kept lower cased as names compare case insensitively (RFC 4343).
+#[derive (Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    name: String,
+    r_type: u16,
+    r_class: u16
+}
+
+impl Key {
+    pub fn new(name: &str, r_type: u16, r_class: u16) -> Key {
+        Key {
+            name: name.to_ascii_lowercase(),
+            r_type: r_type,
+            r_class: r_class
+        }
+    }
+
+    pub fn for_question(q: &Question) -> Key {
+        Key::new(&q.name(), q.q_type().code(), q.q_class().code())
+    }
+
+    pub fn for_record(r: &ResourceRecord) -> Key {
+        Key::new(&r.name(), r.r_type, r.r_class)
+    }
+
+    /// The key of an NXDOMAIN for the name.
+    fn for_name(&self) -> Key {
+        Key {
+            r_type: TYPE_NAME,
+            .. self.clone()
+        }
+    }
+}
+
+/// How far cached records are trusted, after the section of the response
+/// they came in (RFC 2181 section 5.4.1).
+#[derive (Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Trust {
+    /// the authority and additional sections
+    Additional,
+    /// the answer section
+    Answer
+}
+
+/// A summary of a cache entry, for inspection from the outside.
+#[derive (Debug, Clone)]
+pub struct EntryInfo {
+    pub name: String,
+    pub r_type: u16,
+    pub r_class: u16,
+    /// seconds left to live, negative once the entry is only kept around
+    /// to be served stale
+    pub ttl: i32,
+    /// the response code of a negative entry
+    pub rcode: Option<u16>,
+    pub records: Vec<ResourceRecord>
+}
+
+/// Whether `name` is `parent` or a name below it, the empty name and the
+/// root are the parent of everything.
+fn is_subdomain(name: &str, parent: &str) -> bool {
+    let parent = parent.trim_end_matches('.');
+
+    parent.is_empty() || name == parent ||
+        (name.ends_with(parent) && name[..name.len() - parent.len()].ends_with('.'))
+}
+
+/// The names the answer section of `m` is about, lower cased: the name
+/// asked for followed by the targets of the CNAMEs leading on from it.
+fn answer_names(m: &Message) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut name = match m.questions().first() {
+        Some(q) => q.name().to_ascii_lowercase(),
+        None => return names
+    };
+
+    loop {
+        let target = m.answers.iter()
+            .filter(|r| r.name().to_ascii_lowercase() == name)
+            .filter_map(|r| match r.r_data {
+                ResourceData::CNAME(ref target) => Some(String::from_utf8_lossy(target).to_ascii_lowercase()),
+                _ => None
+            })
+            .next();
+
+        names.push(name);
+        match target {
+            Some(target) if !names.contains(&target) && names.len() < MAX_CNAME_CHAIN => name = target,
+            _ => return names
+        }
+    }
+}
+
 #[derive (Debug)]
 struct Entry {
-    record: ResourceRecord,
-    committed_at: f64
+    answer: Answer,
+    ttl: i32,
+    committed_at: f64,
+    last_used: u64,
+    size: usize,
+    hits: u32,
+    /// when the entry was last handed out for prefetching
+    prefetched_at: Option<f64>,
+    trust: Trust
 }
 
 impl Entry {
-    fn new(r: ResourceRecord) -> Entry {
+    fn new(answer: Answer, ttl: i32, trust: Trust) -> Entry {
         Entry {
-            record: r,
-            committed_at: time::precise_time_s()
+            size: mem::size_of::<Entry>() + answer.size(),
+            answer: answer,
+            ttl: ttl,
+            trust: trust,
+            committed_at: time::precise_time_s(),
+            last_used: 0,
+            hits: 0,
+            prefetched_at: None
+        }
+    }
+
+    fn ttl_remaining(&self, now: f64) -> i32 {
+        self.ttl - (now - self.committed_at) as i32
+    }
+
+    fn is_expired(&self, now: f64) -> bool {
+        self.ttl_remaining(now) <= 0
+    }
+
+    fn is_past_stale(&self, now: f64, window: i32) -> bool {
+        self.ttl_remaining(now) + window <= 0
+    }
+
+    /// Where the entry's CNAME record points, lower cased.
+    fn cname_target(&self) -> Option<String> {
+        match self.answer {
+            Answer::Records(ref records) => match records.first().map(|r| &r.r_data) {
+                Some(&ResourceData::CNAME(ref target)) => Some(String::from_utf8_lossy(target).to_ascii_lowercase()),
+                _ => None
+            },
+            _ => None
         }
     }
 }
 
 #[derive (Debug)]
 pub struct Cache {
-    entries: HashMap<String, Entry>
+    entries: HashMap<Key, Entry>,
+    /// entry keys ordered from least to most recently used
+    lru: BTreeMap<u64, Key>,
+    tick: u64,
+    bytes: usize,
+    options: Options,
+    stats: Stats
 }
 
 impl Cache {
     pub fn new() -> Cache {
+        Cache::with_options(Options::default())
+    }
+
+    pub fn with_options(options: Options) -> Cache {
         Cache{
-            entries: HashMap::new()
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+            options: options,
+            stats: Stats::default()
+        }
+    }
+
+    /// The keys of the entries answering for `key`: its own, else the
+    /// NXDOMAIN of the name, else the CNAME of the name followed by the keys
+    /// answering for where it points. CNAME loops and chains longer than
+    /// `MAX_CNAME_CHAIN` find nothing.
+    fn find(&self, key: &Key) -> Option<Vec<Key>> {
+        let mut keys = Vec::new();
+        let mut key = key.clone();
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let name_key = key.for_name();
+            let cname_key = Key { r_type: TYPE_CNAME, .. key.clone() };
+
+            if self.entries.contains_key(&key) {
+                keys.push(key);
+                return Some(keys)
+            } else if self.entries.contains_key(&name_key) {
+                keys.push(name_key);
+                return Some(keys)
+            }
+
+            let target = match self.entries.get(&cname_key).and_then(Entry::cname_target) {
+                Some(target) => target,
+                None => return None
+            };
+
+            keys.push(cname_key);
+            if keys.iter().any(|k| k.name == target) {
+                return None
+            }
+
+            key = Key { name: target, .. key };
+        }
+
+        None
+    }
+
+    /// The answer made up of the entries under `keys`, the records of the
+    /// CNAMEs leading to the last one go first.
+    fn answer_for(&self, keys: &[Key]) -> Answer {
+        let (last, cnames) = keys.split_last().expect("no keys");
+        let mut records = Vec::new();
+
+        for key in cnames.iter() {
+            if let Answer::Records(ref cname) = self.entries[key].answer {
+                records.extend(cname.iter().cloned());
+            }
+        }
+
+        self.entries[last].answer.clone().through(records)
+    }
+
+    /// The least TTL left among the entries under `keys`.
+    fn ttl_remaining(&self, keys: &[Key], now: f64) -> i32 {
+        keys.iter().map(|key| self.entries[key].ttl_remaining(now)).min().unwrap_or(0)
+    }
+
+    /// Looks `key` up, following cached CNAMEs. The records of an answer
+    /// reached through them all carry the TTL of the one expiring first.
+    pub fn get(&mut self, key: &Key) -> Option<Answer> {
+        let now = time::precise_time_s();
+        let keys = match self.find(key) {
+            Some(keys) => keys,
+            None => {
+                self.stats.misses += 1;
+                return None
+            }
+        };
+
+        let ttl = self.ttl_remaining(&keys, now);
+        if ttl <= 0 {
+            self.stats.misses += 1;
+            return None
+        }
+
+        for key in keys.iter() {
+            if let Some(entry) = self.entries.get_mut(key) {
+                entry.hits += 1;
+            }
+            self.touch(key);
+        }
+
+        self.stats.hits += 1;
+        Some(self.answer_for(&keys).with_ttl(ttl))
+    }
+
+    /// Like `get` but entries whose TTL ran out are still answered with as
+    /// long as they are within the stale window, for when upstreams are
+    /// failing (RFC 8767).
+    pub fn get_stale(&mut self, key: &Key) -> Option<Answer> {
+        let now = time::precise_time_s();
+        let window = self.options.stale_window;
+        let keys = match self.find(key) {
+            Some(keys) => keys,
+            None => return None
+        };
+
+        let ttl = match self.ttl_remaining(&keys, now) {
+            ttl if ttl > 0 => ttl,
+            ttl if ttl + window > 0 => {
+                self.stats.stale_hits += 1;
+                STALE_TTL
+            },
+            _ => return None
+        };
+
+        for key in keys.iter() {
+            self.touch(key);
+        }
+
+        Some(self.answer_for(&keys).with_ttl(ttl))
+    }
+
+    /// Whether the entry answering for `key` that expires first is popular
+    /// and within the last tenth of its TTL, in which case it should be
+    /// refreshed before it expires. An entry is handed out for prefetching
+    /// again only if the last one didn't replace it in time.
+    pub fn prefetch_due(&mut self, key: &Key) -> bool {
+        let now = time::precise_time_s();
+        let hits = self.options.prefetch_hits;
+        let key = match self.find(key) {
+            Some(keys) => match keys.into_iter().min_by_key(|key| self.entries[key].ttl_remaining(now)) {
+                Some(key) => key,
+                None => return false
+            },
+            None => return false
+        };
+
+        match self.entries.get_mut(&key) {
+            Some(ref mut entry) if hits > 0 && entry.hits >= hits
+                && entry.prefetched_at.map_or(true, |t| now - t >= PREFETCH_RETRY_SECS)
+                && entry.ttl_remaining(now) <= entry.ttl / 10 => {
+                entry.prefetched_at = Some(now);
+            },
+            _ => return false
+        }
+
+        self.stats.prefetches += 1;
+        true
+    }
+
+    /// Marks the entry as the most recently used one.
+    fn touch(&mut self, key: &Key) {
+        self.tick += 1;
+
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            self.lru.insert(self.tick, key.clone());
+            entry.last_used = self.tick;
+        }
+    }
+
+    fn insert(&mut self, key: Key, mut entry: Entry) {
+        self.remove(&key);
+
+        self.tick += 1;
+        entry.last_used = self.tick;
+
+        self.bytes += entry.size;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(key, entry);
+        self.stats.insertions += 1;
+
+        self.evict();
+    }
+
+    fn remove(&mut self, key: &Key) -> Option<Entry> {
+        self.entries.remove(key).map(|entry| {
+            self.lru.remove(&entry.last_used);
+            self.bytes -= entry.size;
+            entry
+        })
+    }
+
+    /// Drops least recently used entries until the cache fits its budget.
+    fn evict(&mut self) {
+        while self.entries.len() > self.options.max_entries || self.bytes > self.options.max_bytes {
+            let key = match self.lru.values().next() {
+                Some(key) => key.clone(),
+                None => break
+            };
+
+            self.remove(&key);
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn clamp_ttl(&self, ttl: i32, max_ttl: i32) -> i32 {
+        cmp::min(max_ttl, cmp::max(self.options.min_ttl, ttl))
+    }
+
+    /// Replaces the RRset stored under `key`, the set's TTL is the lowest
+    /// of its records clamped to the configured bounds.
+    pub fn set(&mut self, key: Key, records: Vec<ResourceRecord>) {
+        self.set_with_trust(key, records, Trust::Answer)
+    }
+
+    /// Like `set`, but a live RRset that is trusted more is left alone.
+    fn set_with_trust(&mut self, key: Key, records: Vec<ResourceRecord>, trust: Trust) {
+        let now = time::precise_time_s();
+        match self.entries.get(&key) {
+            Some(entry) if entry.trust > trust && !entry.is_expired(now) => return,
+            _ => ()
+        }
+
+        let ttl = match records.iter().map(|r| r.r_ttl).min() {
+            Some(ttl) => self.clamp_ttl(ttl, self.options.max_ttl),
+            None => return
+        };
+
+        if ttl <= 0 {
+            return
+        }
+
+        self.insert(key, Entry::new(Answer::Records(records), ttl, trust));
+    }
+
+    /// Remembers that what `key` stands for doesn't exist, the denial
+    /// lives for the lesser of the SOA's TTL and its minimum field.
+    fn set_negative(&mut self, key: Key, rcode: u16, soa: &ResourceRecord) {
+        let ttl = match soa.r_data {
+            ResourceData::SOA { minimum, .. } => {
+                self.clamp_ttl(cmp::min(soa.r_ttl, minimum as i32), self.options.max_negative_ttl)
+            },
+            _ => return
+        };
+
+        if ttl <= 0 {
+            return
+        }
+
+        self.insert(key, Entry::new(Answer::Negative(rcode, Vec::new(), soa.clone()), ttl, Trust::Answer));
+    }
+
+    /// Stores the RRsets of an upstream response. Answers are only taken
+    /// for the name asked and the CNAMEs it leads through, authority records
+    /// only for zones those names are in and additional records only from
+    /// within those zones, so a server can't plant records for names it has
+    /// no say over. Neither replaces an RRset that came as an answer.
+    ///
+    /// NXDOMAIN and NODATA are cached against the name the CNAMEs end at,
+    /// an NXDOMAIN for every type of it. They need the SOA of the zone to
+    /// tell for how long, without it or with neither records nor a denial
+    /// (a referral) only the RRsets are stored.
+    pub fn insert_message(&mut self, m: &Message) {
+        if m.is_truncated() {
+            return
+        }
+
+        let q = match m.questions().first() {
+            Some(q) => q,
+            None => return
+        };
+
+        let names = answer_names(m);
+        // the root is left out, it would vouch for anything
+        let zones: Vec<String> = m.name_server.iter()
+            .map(|r| r.name().to_ascii_lowercase())
+            .filter(|zone| !zone.is_empty() && names.iter().any(|name| is_subdomain(name, zone)))
+            .collect();
+
+        let answers = m.answers.iter()
+            .filter(|r| names.contains(&r.name().to_ascii_lowercase()));
+        // a SOA comes along to time denials, its TTL may be cut down to that
+        let authority = m.name_server.iter()
+            .filter(|r| r.r_type != TYPE_SOA && zones.contains(&r.name().to_ascii_lowercase()));
+        let additional = m.additional.iter()
+            .filter(|r| zones.iter().any(|zone| is_subdomain(&r.name().to_ascii_lowercase(), zone)));
+
+        let q_type = q.q_type().code();
+        let answered = answers.clone().any(|r| r.r_type == q_type || q_type == TYPE_ANY);
+        let target = Key::new(&names[names.len() - 1], q_type, q.q_class().code());
+        let soa = m.name_server.iter()
+            .find(|r| r.r_type == TYPE_SOA && is_subdomain(&target.name, &r.name().to_ascii_lowercase()));
+
+        match (m.return_code(), soa) {
+            (RCODE_NXDOMAIN, Some(soa)) => self.set_negative(target.for_name(), RCODE_NXDOMAIN, soa),
+            (RCODE_NXDOMAIN, None) => (),
+            (0, Some(soa)) if !answered => self.set_negative(target, 0, soa),
+            (0, _) => (),
+            _ => return
+        }
+
+        self.insert_rrsets(answers, Trust::Answer);
+        self.insert_rrsets(authority.chain(additional), Trust::Additional);
+    }
+
+    fn insert_rrsets<'a, I: Iterator<Item=&'a ResourceRecord>>(&mut self, records: I, trust: Trust) {
+        let mut rrsets: HashMap<Key, Vec<ResourceRecord>> = HashMap::new();
+
+        for record in records {
+            if record.is_self_contained() {
+                rrsets.entry(Key::for_record(record)).or_insert_with(Vec::new).push(record.clone());
+            }
+        }
+
+        for (key, records) in rrsets.into_iter() {
+            self.set_with_trust(key, records, trust);
+        }
+    }
+
+    /// Drops every entry that expired longer ago than the stale window,
+    /// returning how many were removed.
+    pub fn sweep(&mut self) -> usize {
+        let now = time::precise_time_s();
+        let window = self.options.stale_window;
+
+        let expired: Vec<Key> = self.entries.iter()
+            .filter(|&(_, entry)| entry.is_past_stale(now, window))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in expired.iter() {
+            self.remove(key);
+        }
+
+        self.stats.expirations += expired.len() as u64;
+        expired.len()
+    }
+
+    /// Writes the live entries out so they can be loaded back after a
+    /// restart, returning how many were written. The format, with all
+    /// integers big endian, is:
+    ///
+    /// ```text
+    /// magic     4 bytes  "PDNS"
+    /// version   u16      1
+    /// then until the end of the file, one per entry:
+    ///   expires   i64      unix time at which the entry's TTL runs out
+    ///   name_len  u8
+    ///   name      name_len bytes, the lower cased owner name in text form
+    ///   type      u16
+    ///   class     u16
+    ///   negative  u8       1 for a cached NXDOMAIN or NODATA, 0 otherwise
+    ///   rcode     u16      the response code of a negative entry
+    ///   count     u16      number of records that follow
+    ///   records   resource records in wire format with uncompressed names,
+    ///             the SOA of a negative entry, TTL fields are not used
+    /// ```
+    pub fn save<W: Write>(&self, w: &mut W) -> Result<usize, Error> {
+        let now = time::precise_time_s();
+        let wall = time::get_time().sec;
+        let mut count = 0;
+
+        try!(w.write_all(FILE_MAGIC));
+        try!(w.write_u16::<BigEndian>(FILE_VERSION));
+
+        for (key, entry) in self.entries.iter() {
+            if entry.is_expired(now) || key.name.len() > NAMES_MAX_LENGTH {
+                continue
+            }
+
+            try!(w.write_i64::<BigEndian>(wall + entry.ttl_remaining(now) as i64));
+            try!(w.write_u8(key.name.len() as u8));
+            try!(w.write_all(key.name.as_bytes()));
+            try!(w.write_u16::<BigEndian>(key.r_type));
+            try!(w.write_u16::<BigEndian>(key.r_class));
+
+            match entry.answer {
+                Answer::Records(ref records) => {
+                    try!(w.write_u8(0));
+                    try!(w.write_u16::<BigEndian>(0));
+                    try!(w.write_u16::<BigEndian>(records.len() as u16));
+                    for r in records.iter() {
+                        try!(r.write(w));
+                    }
+                },
+                Answer::Negative(rcode, _, ref soa) => {
+                    try!(w.write_u8(1));
+                    try!(w.write_u16::<BigEndian>(rcode));
+                    try!(w.write_u16::<BigEndian>(1));
+                    try!(soa.write(w));
+                }
+            }
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Loads entries written by `save`, those that expired in the meantime
+    /// are skipped. Returns how many entries were loaded.
+    pub fn load(&mut self, b: &[u8]) -> Result<usize, Error> {
+        if b.len() < FILE_MAGIC.len() || &b[..FILE_MAGIC.len()] != FILE_MAGIC {
+            return Err(Error::String("not a cache file"))
+        }
+
+        let mut c = Cursor::new(b);
+        c.set_position(FILE_MAGIC.len() as u64);
+
+        if try!(c.read_u16::<BigEndian>()) != FILE_VERSION {
+            return Err(Error::String("unsupported cache file version"))
+        }
+
+        let wall = time::get_time().sec;
+        let mut count = 0;
+
+        while (c.position() as usize) < b.len() {
+            let expires = try!(c.read_i64::<BigEndian>());
+
+            let name_len = try!(c.read_u8()) as usize;
+            let pos = c.position() as usize;
+            if pos + name_len > b.len() {
+                return Err(Error::String("truncated cache file"))
+            }
+            let name = String::from_utf8_lossy(&b[pos..pos + name_len]).into_owned();
+            c.set_position((pos + name_len) as u64);
+
+            let r_type = try!(c.read_u16::<BigEndian>());
+            let r_class = try!(c.read_u16::<BigEndian>());
+            let negative = try!(c.read_u8()) != 0;
+            let rcode = try!(c.read_u16::<BigEndian>());
+            let n = try!(c.read_u16::<BigEndian>());
+
+            let mut records = Vec::with_capacity(n as usize);
+            for _ in 0..n {
+                let pos = c.position() as usize;
+                let (record, len) = try!(ResourceRecord::read(&b[pos..]));
+                c.set_position((pos + len) as u64);
+                records.push(record);
+            }
+
+            let answer = if negative {
+                match records.pop() {
+                    Some(soa) => Answer::Negative(rcode, Vec::new(), soa),
+                    None => return Err(Error::String("negative cache entry without a SOA"))
+                }
+            } else {
+                Answer::Records(records)
+            };
+
+            let max_ttl = if negative { self.options.max_negative_ttl } else { self.options.max_ttl };
+            let ttl = cmp::min(expires - wall, max_ttl as i64);
+            if ttl <= 0 {
+                continue
+            }
+
+            // where the records came from isn't kept, they're trusted least
+            self.insert(Key::new(&name, r_type, r_class), Entry::new(answer, ttl as i32, Trust::Additional));
+            count += 1;
         }
+
+        Ok(count)
+    }
+
+    /// Lists the entries at or below `name`, sorted by name.
+    pub fn list(&self, name: &str) -> Vec<EntryInfo> {
+        let now = time::precise_time_s();
+        let name = name.to_ascii_lowercase();
+
+        let mut entries: Vec<EntryInfo> = self.entries.iter()
+            .filter(|&(key, _)| is_subdomain(&key.name, &name))
+            .map(|(key, entry)| {
+                let (rcode, records) = match entry.answer {
+                    Answer::Records(ref records) => (None, records.clone()),
+                    Answer::Negative(rcode, _, ref soa) => (Some(rcode), vec![soa.clone()])
+                };
+
+                EntryInfo {
+                    name: key.name.clone(),
+                    r_type: key.r_type,
+                    r_class: key.r_class,
+                    ttl: entry.ttl_remaining(now),
+                    rcode: rcode,
+                    records: records
+                }
+            })
+            .collect();
+
+        entries.sort_by(|a, b| (&a.name, a.r_type).cmp(&(&b.name, b.r_type)));
+        entries
     }
 
-    pub fn get(&self, key: &str) -> Option<&ResourceRecord> {
-        self.entries.get(key).map(|entry| &entry.record)
+    fn remove_matching<F: Fn(&Key) -> bool>(&mut self, f: F) -> usize {
+        let keys: Vec<Key> = self.entries.keys().filter(|key| f(key)).cloned().collect();
+
+        for key in keys.iter() {
+            self.remove(key);
+        }
+
+        keys.len()
+    }
+
+    /// Removes every type cached for `name`, returning how many entries went.
+    pub fn flush(&mut self, name: &str) -> usize {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.remove_matching(|key| key.name == name)
+    }
+
+    /// Removes `name` and everything below it.
+    pub fn flush_tree(&mut self, name: &str) -> usize {
+        let name = name.to_ascii_lowercase();
+        self.remove_matching(|key| is_subdomain(&key.name, &name))
+    }
+
+    pub fn flush_all(&mut self) -> usize {
+        let count = self.entries.len();
+
+        self.entries.clear();
+        self.lru.clear();
+        self.bytes = 0;
+        count
     }
 
-    pub fn set(&mut self, key: &str, record: &ResourceRecord) {
-        self.entries.insert(key.to_owned(), Entry::new(record.clone()));
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Approximate memory held by the cached entries.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use dns::*;
+    use std::net::Ipv4Addr;
+
+    fn records(answer: Option<Answer>) -> Vec<ResourceRecord> {
+        match answer {
+            Some(Answer::Records(records)) => records,
+            a => panic!("expected records, got {:?}", a)
+        }
+    }
+
+    fn soa(ttl: i32, minimum: u32) -> ResourceRecord {
+        ResourceRecord {
+            r_name: "com".bytes().collect(),
+            r_type: 6,
+            r_class: 1,
+            r_ttl: ttl,
+            r_data: ResourceData::SOA {
+                mname: "a.gtld-servers.net".bytes().collect(),
+                rname: "nstld.verisign-grs.com".bytes().collect(),
+                serial: 1449658420,
+                refresh: 1800,
+                retry: 900,
+                expire: 604800,
+                minimum: minimum
+            }
+        }
+    }
+
+    /// An answer to `request` without any records.
+    fn empty_response(request: &Message, rcode: u16) -> Message {
+        let mut m = request.clone();
+        m.flags = request.response_flags();
+        m.set_return_code(rcode);
+        m
+    }
+
+    #[test]
+    fn insert_response() {
+        let msg = Message::new(include_bytes!("../test/dns_response.bin")).unwrap();
+        let mut cache = Cache::new();
+        let key = Key::new("fark.com", 1, 1);
+
+        assert!(cache.get(&key).is_none());
+
+        cache.insert_message(&msg);
+
+        let records = records(cache.get(&key));
+        assert_eq!(1, records.len());
+        assert_eq!(ResourceData::A(Ipv4Addr::new(64,191,171,200)), records[0].r_data);
+        assert!(records[0].r_ttl <= msg.answers[0].r_ttl);
+    }
+
+    #[test]
+    fn rrsets() {
+        let msg = Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap();
+        let mut cache = Cache::new();
+
+        cache.insert_message(&msg);
+
+        assert_eq!(1, cache.len());
+        assert_eq!(4, records(cache.get(&Key::new("shops.shopify.com", 1, 1))).len());
+        assert_eq!(4, records(cache.get(&Key::new("SHOPS.Shopify.COM", 1, 1))).len());
+        assert!(cache.get(&Key::new("shops.shopify.com", 28, 1)).is_none());
+    }
+
+    fn record(name: &str, r_type: u16, r_data: ResourceData) -> ResourceRecord {
+        ResourceRecord {
+            r_name: name.bytes().collect(),
+            r_type: r_type,
+            r_class: 1,
+            r_ttl: 300,
+            r_data: r_data
+        }
+    }
+
+    #[test]
+    fn bailiwick() {
+        let mut msg = Message::new(include_bytes!("../test/dns_response.bin")).unwrap();
+        let a = |addr| ResourceData::A(Ipv4Addr::new(10, 0, 0, addr));
+        let ns = |name: &str| ResourceData::NS(name.bytes().collect());
+
+        msg.answers.push(record("bank.com", 1, a(1)));
+        msg.name_server.push(record("fark.com", 2, ns("ns1.fark.com")));
+        msg.name_server.push(record("bank.com", 2, ns("ns1.fark.com")));
+        msg.additional.push(record("ns1.fark.com", 1, a(2)));
+        msg.additional.push(record("fark.com", 1, a(3)));
+        msg.additional.push(record("ns.bank.com", 1, a(4)));
+
+        let mut cache = Cache::new();
+        cache.insert_message(&msg);
+
+        assert_eq!(3, cache.len());
+        assert_eq!(1, records(cache.get(&Key::new("fark.com", 2, 1))).len());
+        assert_eq!(a(2), records(cache.get(&Key::new("ns1.fark.com", 1, 1)))[0].r_data);
+        // the answer isn't replaced by the additional copy
+        assert_eq!(ResourceData::A(Ipv4Addr::new(64,191,171,200)), records(cache.get(&Key::new("fark.com", 1, 1)))[0].r_data);
+
+        // nor by one from another response
+        let mut other = Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap();
+        other.name_server.push(record("com", 2, ns("a.gtld-servers.net")));
+        other.additional.push(record("fark.com", 1, a(5)));
+        cache.insert_message(&other);
+
+        assert_eq!(ResourceData::A(Ipv4Addr::new(64,191,171,200)), records(cache.get(&Key::new("fark.com", 1, 1)))[0].r_data);
+        assert_eq!(1, records(cache.get(&Key::new("com", 2, 1))).len());
+    }
+
+    #[test]
+    fn cname_chain() {
+        let request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        let mut msg = empty_response(&request, 0);
+        msg.answers.push(record("fark.com", 5, ResourceData::CNAME("www.fark.net".bytes().collect())));
+        msg.answers.push(record("www.fark.net", 5, ResourceData::CNAME("cdn.example".bytes().collect())));
+        msg.answers.push(record("cdn.example", 1, ResourceData::A(Ipv4Addr::new(10, 0, 0, 1))));
+        msg.answers.push(record("elsewhere.example", 1, ResourceData::A(Ipv4Addr::new(10, 0, 0, 2))));
+
+        let mut cache = Cache::new();
+        cache.insert_message(&msg);
+
+        assert_eq!(3, cache.len());
+        assert!(cache.get(&Key::new("cdn.example", 1, 1)).is_some());
+        assert!(cache.get(&Key::new("elsewhere.example", 1, 1)).is_none());
+    }
+
+    #[test]
+    fn cname_lookup() {
+        let request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        let mut msg = empty_response(&request, 0);
+        msg.answers.push(record("fark.com", 5, ResourceData::CNAME("www.fark.net".bytes().collect())));
+        msg.answers.push(record("www.fark.net", 5, ResourceData::CNAME("cdn.example".bytes().collect())));
+        msg.answers.push(record("cdn.example", 1, ResourceData::A(Ipv4Addr::new(10, 0, 0, 1))));
+        msg.answers[1].r_ttl = 100;
+
+        let mut cache = Cache::new();
+        cache.insert_message(&msg);
+
+        let key = Key::for_question(&request.questions()[0]);
+        let answer = records(cache.get(&key));
+        let types: Vec<u16> = answer.iter().map(|r| r.r_type).collect();
+        assert_eq!(vec![5, 5, 1], types);
+        assert_eq!(msg.answers[2].r_data, answer[2].r_data);
+        assert!(answer.iter().all(|r| r.r_ttl <= 100));
+        assert_eq!(1, cache.stats().hits);
+
+        // the chain is as good as its first link to expire
+        cache.entries.get_mut(&Key::new("www.fark.net", 5, 1)).unwrap().committed_at -= 100.0;
+        assert!(cache.get(&key).is_none());
+        assert_eq!(STALE_TTL, records(cache.get_stale(&key))[0].r_ttl);
+
+        // nor is a CNAME loop followed round
+        let mut cache = Cache::new();
+        cache.set(Key::new("a.com", 5, 1), vec![record("a.com", 5, ResourceData::CNAME("b.com".bytes().collect()))]);
+        cache.set(Key::new("b.com", 5, 1), vec![record("b.com", 5, ResourceData::CNAME("A.com".bytes().collect()))]);
+        assert!(cache.get(&Key::new("a.com", 1, 1)).is_none());
+        assert_eq!(1, records(cache.get(&Key::new("a.com", 5, 1))).len());
+    }
+
+    #[test]
+    fn ttl_clamps() {
+        let msg = Message::new(include_bytes!("../test/dns_response.bin")).unwrap();
+        let mut record = msg.answers[0].clone();
+
+        let mut cache = Cache::with_options(Options { min_ttl: 60, max_ttl: 120, ..Options::default() });
+
+        let low = Key::new("low.com", 1, 1);
+        let high = Key::new("high.com", 1, 1);
+
+        record.r_ttl = 1;
+        cache.set(low.clone(), vec![record.clone()]);
+        record.r_ttl = 7 * 24 * 60 * 60;
+        cache.set(high.clone(), vec![record.clone()]);
+
+        assert!(records(cache.get(&low))[0].r_ttl > 1);
+        assert!(records(cache.get(&high))[0].r_ttl <= 120);
+    }
+
+    #[test]
+    fn expired_entries() {
+        let msg = Message::new(include_bytes!("../test/dns_response.bin")).unwrap();
+        let mut cache = Cache::new();
+
+        let key = Key::new("fark.com", 1, 1);
+
+        cache.insert_message(&msg);
+        assert!(cache.get_stale(&key).is_some());
+        assert_eq!(0, cache.stats.stale_hits);
+        cache.entries.get_mut(&key).unwrap().committed_at -= msg.answers[0].r_ttl as f64;
+
+        assert!(cache.get(&key).is_none());
+        assert_eq!(0, cache.sweep());
+        assert_eq!(STALE_TTL, records(cache.get_stale(&key))[0].r_ttl);
+        assert_eq!(1, cache.stats.stale_hits);
+
+        cache.entries.get_mut(&key).unwrap().committed_at -= Options::default().stale_window as f64;
+
+        assert!(cache.get_stale(&key).is_none());
+        assert_eq!(1, cache.sweep());
+        assert_eq!(0, cache.len());
+    }
+
+    #[test]
+    fn negative_answers() {
+        let request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        let key = Key::for_question(&request.questions()[0]);
+        let mut cache = Cache::new();
+
+        let mut nxdomain = empty_response(&request, 3);
+        nxdomain.name_server.push(soa(900, 300));
+
+        cache.insert_message(&nxdomain);
+
+        match cache.get(&key) {
+            Some(Answer::Negative(3, _, ref record)) => assert!(record.r_ttl <= 300),
+            a => panic!("expected NXDOMAIN, got {:?}", a)
+        }
+
+        let mut nodata = empty_response(&request, 0);
+        nodata.name_server.push(soa(60, 86400));
+
+        cache.insert_message(&nodata);
+
+        match cache.get(&key) {
+            Some(Answer::Negative(0, _, ref record)) => assert!(record.r_ttl <= 60),
+            a => panic!("expected NODATA, got {:?}", a)
+        }
+
+        // without a SOA there's no TTL to cache the denial for
+        let no_soa = empty_response(&request, 3);
+
+        let mut cache = Cache::new();
+        cache.insert_message(&no_soa);
+        assert!(cache.get(&key).is_none());
+    }
+
+    #[test]
+    fn negative_answer_scope() {
+        let request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        let mut cache = Cache::new();
+
+        // an NXDOMAIN holds for every type of the name
+        let mut nxdomain = empty_response(&request, 3);
+        nxdomain.name_server.push(soa(900, 300));
+        cache.insert_message(&nxdomain);
+
+        match cache.get(&Key::new("fark.com", 28, 1)) {
+            Some(Answer::Negative(3, _, _)) => (),
+            a => panic!("expected NXDOMAIN, got {:?}", a)
+        }
+        assert!(cache.get(&Key::new("www.fark.com", 1, 1)).is_none());
+
+        // a NODATA only for the type asked
+        let mut cache = Cache::new();
+        let mut nodata = empty_response(&request, 0);
+        nodata.name_server.push(soa(900, 300));
+        cache.insert_message(&nodata);
+
+        assert!(cache.get(&Key::new("fark.com", 1, 1)).is_some());
+        assert!(cache.get(&Key::new("fark.com", 28, 1)).is_none());
+
+        // the denial is for where the CNAME leads, the CNAME is kept apart
+        let mut cache = Cache::new();
+        let mut nxdomain = empty_response(&request, 3);
+        nxdomain.answers.push(record("fark.com", 5, ResourceData::CNAME("gone.com".bytes().collect())));
+        nxdomain.name_server.push(soa(900, 300));
+        cache.insert_message(&nxdomain);
+
+        assert_eq!(2, cache.len());
+        assert_eq!(1, records(cache.get(&Key::new("fark.com", 5, 1))).len());
+        match cache.get(&Key::new("gone.com", 1, 1)) {
+            Some(Answer::Negative(3, ref cnames, _)) => assert!(cnames.is_empty()),
+            a => panic!("expected NXDOMAIN, got {:?}", a)
+        }
+        match cache.get(&Key::new("fark.com", 1, 1)) {
+            Some(Answer::Negative(3, ref cnames, _)) => assert_eq!(1, cnames.len()),
+            a => panic!("expected NXDOMAIN, got {:?}", a)
+        }
+
+        // a referral denies nothing
+        let mut cache = Cache::new();
+        let mut referral = empty_response(&request, 0);
+        referral.name_server.push(record("fark.com", 2, ResourceData::NS("ns1.fark.com".bytes().collect())));
+        cache.insert_message(&referral);
+
+        assert!(cache.get(&Key::new("fark.com", 1, 1)).is_none());
+        assert_eq!(1, records(cache.get(&Key::new("fark.com", 2, 1))).len());
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let msg = Message::new(include_bytes!("../test/dns_response.bin")).unwrap();
+        let mut cache = Cache::with_options(Options { max_entries: 2, ..Options::default() });
+
+        let keys: Vec<Key> = ["a.com", "b.com", "c.com"].iter().map(|n| Key::new(n, 1, 1)).collect();
+
+        cache.set(keys[0].clone(), msg.answers.iter().cloned().collect());
+        cache.set(keys[1].clone(), msg.answers.iter().cloned().collect());
+        assert!(cache.get(&keys[0]).is_some());
+        cache.set(keys[2].clone(), msg.answers.iter().cloned().collect());
+
+        assert_eq!(2, cache.len());
+        assert_eq!(1, cache.stats().evictions);
+        assert!(cache.get(&keys[0]).is_some());
+        assert!(cache.get(&keys[1]).is_none());
+        assert!(cache.get(&keys[2]).is_some());
+
+        let mut cache = Cache::with_options(Options { max_bytes: 0, ..Options::default() });
+        cache.set(keys[0].clone(), msg.answers.iter().cloned().collect());
+        assert_eq!(0, cache.len());
+        assert_eq!(0, cache.bytes());
+    }
+
+    #[test]
+    fn prefetch() {
+        let msg = Message::new(include_bytes!("../test/dns_response.bin")).unwrap();
+        let mut cache = Cache::with_options(Options { prefetch_hits: 2, ..Options::default() });
+        let key = Key::new("fark.com", 1, 1);
+
+        cache.insert_message(&msg);
+        cache.get(&key);
+        cache.get(&key);
+        assert!(!cache.prefetch_due(&key));
+
+        let ttl = cache.entries[&key].ttl;
+        cache.entries.get_mut(&key).unwrap().committed_at -= (ttl - ttl / 20) as f64;
+
+        assert!(cache.prefetch_due(&key));
+        assert!(!cache.prefetch_due(&key));
+        assert_eq!(1, cache.stats().prefetches);
+
+        // the prefetch went nowhere, the entry is handed out again later
+        *cache.entries.get_mut(&key).unwrap().prefetched_at.as_mut().unwrap() -= PREFETCH_RETRY_SECS;
+        assert!(cache.prefetch_due(&key));
+        assert_eq!(2, cache.stats().prefetches);
+
+        cache.insert_message(&msg);
+        assert!(!cache.prefetch_due(&key));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        let mut nxdomain = empty_response(&request, 3);
+        nxdomain.name_server.push(soa(900, 300));
+
+        let mut cache = Cache::new();
+        cache.insert_message(&Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap());
+        cache.insert_message(&nxdomain);
+
+        let mut bytes = Vec::new();
+        assert_eq!(2, cache.save(&mut bytes).unwrap());
+
+        let mut loaded = Cache::new();
+        assert_eq!(2, loaded.load(&bytes).unwrap());
+
+        let records = records(loaded.get(&Key::new("shops.shopify.com", 1, 1)));
+        assert_eq!(4, records.len());
+        assert_eq!(ResourceData::A(Ipv4Addr::new(23,227,38,71)), records[0].r_data);
+
+        match loaded.get(&Key::for_question(&request.questions()[0])) {
+            Some(Answer::Negative(3, _, ref record)) => assert_eq!(soa(900, 300).r_data, record.r_data),
+            a => panic!("expected NXDOMAIN, got {:?}", a)
+        }
+
+        // negative entries are held to the negative limit
+        let mut loaded = Cache::with_options(Options { max_negative_ttl: 30, ..Options::default() });
+        assert_eq!(2, loaded.load(&bytes).unwrap());
+        let ttls: Vec<i32> = loaded.list("").iter().filter(|e| e.rcode.is_some()).map(|e| e.ttl).collect();
+        assert_eq!(vec![30], ttls);
+
+        // expiry times are absolute, an entry that ran out while the file
+        // sat on disk is dropped
+        let mut cache = Cache::new();
+        cache.insert_message(&nxdomain);
+        let mut bytes = Vec::new();
+        cache.save(&mut bytes).unwrap();
+        for b in bytes[6..14].iter_mut() {
+            *b = 0;
+        }
+        assert_eq!(0, Cache::new().load(&bytes).unwrap());
+
+        assert!(Cache::new().load(b"not a cache").is_err());
+    }
+
+    #[test]
+    fn flushing() {
+        let mut cache = Cache::new();
+        cache.insert_message(&Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap());
+        cache.insert_message(&Message::new(include_bytes!("../test/dns_response.bin")).unwrap());
+
+        assert_eq!(2, cache.list("").len());
+        assert_eq!(1, cache.list("shopify.com").len());
+        assert_eq!(4, cache.list("SHOPIFY.com.")[0].records.len());
+        assert_eq!(0, cache.list("hopify.com").len());
+
+        assert_eq!(0, cache.flush("shopify.com"));
+        assert_eq!(1, cache.flush_tree("shopify.com"));
+        assert_eq!(1, cache.flush("fark.com."));
+        assert_eq!(0, cache.len());
+        assert_eq!(0, cache.bytes());
+
+        cache.insert_message(&Message::new(include_bytes!("../test/dns_response.bin")).unwrap());
+        assert_eq!(1, cache.flush_all());
+        assert!(cache.get(&Key::new("fark.com", 1, 1)).is_none());
     }
 }
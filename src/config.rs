@@ -0,0 +1,445 @@
+//! The configuration file given with `--config`, in TOML:
+//!
+//! ```toml
+//! # addresses to serve DNS on, udp: or tcp: in front for only one of them
+//! listen = ["127.0.0.1:53", "[::1]:53"]
+//! # the cache control interface, unauthenticated so loopback only
+//! control = "127.0.0.1:5300"
+//! user = "nobody"
+//! group = "nogroup"
+//!
+//! [upstream]
+//! # port 53 unless given
+//! servers = ["8.8.8.8", "2001:4860:4860::8888"]
+//! timeout_ms = 10000
+//! # how long the preferred address family gets before the other is asked
+//! fallback_ms = 250
+//! max_queries = 256
+//!
+//! [tcp]
+//! idle_timeout_ms = 10000
+//! max_connections = 128
+//!
+//! [cache]
+//! file = "/var/cache/pdnsd.cache"
+//! min_ttl = 0
+//! max_ttl = 86400
+//! max_negative_ttl = 10800
+//! stale_window = 86400
+//! prefetch_hits = 5
+//! max_entries = 10000
+//! max_bytes = 16777216
+//! ```
+//!
+//! Every key is optional, `user` and `group` only together. Options given on
+//! the command line win over the file and are checked the same way.
+use toml;
+use server;
+use query::MAX_UPSTREAMS;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::{self, Read};
+use std::fmt;
+
+#[derive (Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// line and column, counted from 1, and what's wrong there
+    Syntax(usize, usize, String),
+    /// the key with a bad value and what's wrong with it
+    Invalid(String, String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Syntax(line, col, ref desc) => write!(f, "line {}, column {}: {}", line, col, desc),
+            Error::Invalid(ref key, ref desc) => write!(f, "{}: {}", key, desc)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+fn invalid<T>(key: &str, desc: &str) -> Result<T, Error> {
+    Err(Error::Invalid(key.to_owned(), desc.to_owned()))
+}
+
+/// The command line options that stand in for keys of the file.
+pub const OPTIONS: &'static [&'static str] = &[
+    "listen", "upstream", "control", "user", "group", "min-ttl", "max-ttl",
+    "stale-window", "prefetch-hits", "cache-file", "cache-entries", "cache-bytes"
+];
+
+/// The section and key an option sets, and whether it takes a number.
+fn option_key(option: &str) -> Option<(Option<&'static str>, &'static str, bool)> {
+    match option {
+        "listen" => Some((None, "listen", false)),
+        "upstream" => Some((Some("upstream"), "servers", false)),
+        "control" => Some((None, "control", false)),
+        "user" => Some((None, "user", false)),
+        "group" => Some((None, "group", false)),
+        "min-ttl" => Some((Some("cache"), "min_ttl", true)),
+        "max-ttl" => Some((Some("cache"), "max_ttl", true)),
+        "stale-window" => Some((Some("cache"), "stale_window", true)),
+        "prefetch-hits" => Some((Some("cache"), "prefetch_hits", true)),
+        "cache-file" => Some((Some("cache"), "file", false)),
+        "cache-entries" => Some((Some("cache"), "max_entries", true)),
+        "cache-bytes" => Some((Some("cache"), "max_bytes", true)),
+        _ => None
+    }
+}
+
+#[derive (Debug, Clone)]
+pub struct Config {
+    pub server: server::Config,
+    pub listen: Vec<String>,
+    pub control: Option<SocketAddr>,
+    pub user: Option<String>,
+    pub group: Option<String>
+}
+
+impl Config {
+    pub fn default() -> Config {
+        Config {
+            server: server::Config::default(),
+            listen: vec!["127.0.0.1:9000".to_owned()],
+            control: None,
+            user: None,
+            group: None
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let mut s = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut s));
+        Config::parse(&s)
+    }
+
+    /// Reads the configuration from a file, keys that depend on each other
+    /// are left for `check` once the command line has been applied.
+    pub fn parse(s: &str) -> Result<Config, Error> {
+        let mut parser = toml::Parser::new(s);
+        let table = match parser.parse() {
+            Some(t) => t,
+            None => {
+                let e = &parser.errors[0];
+                let (line, col) = parser.to_linecol(e.lo);
+                return Err(Error::Syntax(line + 1, col + 1, e.desc.clone()))
+            }
+        };
+
+        let mut config = Config::default();
+        try!(config.apply(&table));
+        Ok(config)
+    }
+
+    /// Overrides the configuration with options given on the command line,
+    /// as pairs of option names from `OPTIONS` and their values. Errors
+    /// name the option.
+    pub fn override_with(mut self, options: &[(&str, Vec<String>)]) -> Result<Config, Error> {
+        for &(option, ref values) in options.iter() {
+            let name = format!("--{}", option);
+            let (section, key, number) = match option_key(option) {
+                Some(k) => k,
+                None => return invalid(&name, "unknown option")
+            };
+
+            let value = match (key, values.last()) {
+                ("listen", _) | ("servers", _) => {
+                    toml::Value::Array(values.iter().map(|v| toml::Value::String(v.clone())).collect())
+                },
+                (_, Some(v)) if number => match v.parse() {
+                    Ok(i) => toml::Value::Integer(i),
+                    Err(_) => return invalid(&name, "expected an integer")
+                },
+                (_, Some(v)) => toml::Value::String(v.clone()),
+                (_, None) => continue
+            };
+
+            let mut table = toml::Table::new();
+            table.insert(key.to_owned(), value);
+            if let Some(section) = section {
+                let mut outer = toml::Table::new();
+                outer.insert(section.to_owned(), toml::Value::Table(table));
+                table = outer;
+            }
+
+            try!(self.apply(&table).map_err(|e| match e {
+                Error::Invalid(_, desc) => Error::Invalid(name.clone(), desc),
+                e => e
+            }));
+        }
+        Ok(self)
+    }
+
+    /// Checks the keys that depend on each other, on the configuration
+    /// the file and the command line `options` make up together. Keys that
+    /// clash are named by the option that set them.
+    pub fn check(&self, options: &[(&str, Vec<String>)]) -> Result<(), Error> {
+        let name = |key: &str| {
+            options.iter()
+                .find(|&&(option, _)| option_key(option).map_or(false, |(section, k, _)| {
+                    key == section.map_or(k.to_owned(), |s| format!("{}.{}", s, k))
+                }))
+                .map_or(key.to_owned(), |&(option, _)| format!("--{}", option))
+        };
+
+        let cache = &self.server.cache;
+        if cache.min_ttl > cache.max_ttl {
+            return invalid(&name("cache.min_ttl"), &format!("greater than {}", name("cache.max_ttl")))
+        }
+
+        match (self.user.is_some(), self.group.is_some()) {
+            (true, false) => invalid(&name("user"), &format!("needs {} too", name("group"))),
+            (false, true) => invalid(&name("group"), &format!("needs {} too", name("user"))),
+            _ => Ok(())
+        }
+    }
+
+    fn apply(&mut self, table: &toml::Table) -> Result<(), Error> {
+        let config = self;
+        for (key, value) in table.iter() {
+            match &key[..] {
+                "listen" => {
+                    let listen = try!(strings(key, value));
+                    let mut sockets = 0;
+                    for (i, spec) in listen.iter().enumerate() {
+                        match server::parse_listen(spec) {
+                            Ok((udp, tcp, _)) => sockets += udp as usize + tcp as usize,
+                            Err(_) => return invalid(&format!("{}[{}]", key, i), "expected an address like 127.0.0.1:53, udp:[::1]:53")
+                        }
+                    }
+                    if listen.is_empty() || sockets > server::MAX_LISTENERS {
+                        return invalid(key, &format!("expected 1 to {} sockets", server::MAX_LISTENERS))
+                    }
+                    config.listen = listen;
+                },
+                "control" => {
+                    match try!(string(key, value)).parse::<SocketAddr>() {
+                        Ok(addr) if addr.ip().is_loopback() => config.control = Some(addr),
+                        _ => return invalid(key, "expected a loopback address like 127.0.0.1:5300")
+                    }
+                },
+                "user" => config.user = Some(try!(string(key, value))),
+                "group" => config.group = Some(try!(string(key, value))),
+                "upstream" => try!(upstream_section(key, value, &mut config.server)),
+                "tcp" => try!(tcp_section(key, value, &mut config.server)),
+                "cache" => try!(cache_section(key, value, &mut config.server)),
+                _ => return invalid(key, "unknown key")
+            }
+        }
+        Ok(())
+    }
+}
+
+fn upstream_section(section: &str, value: &toml::Value, config: &mut server::Config) -> Result<(), Error> {
+    for (key, value) in try!(table(section, value)).iter() {
+        let path = format!("{}.{}", section, key);
+        match &key[..] {
+            "servers" => {
+                let servers = try!(strings(&path, value));
+                if servers.is_empty() || servers.len() > MAX_UPSTREAMS {
+                    return invalid(&path, &format!("expected 1 to {} servers", MAX_UPSTREAMS))
+                }
+                config.upstreams = Vec::new();
+                for (i, s) in servers.iter().enumerate() {
+                    match server::parse_upstream(s) {
+                        Ok(addr) => config.upstreams.push(addr),
+                        Err(_) => return invalid(&format!("{}[{}]", path, i), "expected an address like 8.8.8.8 or [2001:4860:4860::8888]:53")
+                    }
+                }
+            },
+            "timeout_ms" => config.query_timeout_ms = try!(integer(&path, value, 1, 60 * 1000)) as u64,
+            "fallback_ms" => config.fallback_ms = try!(integer(&path, value, 0, 60 * 1000)) as u64,
+            "max_queries" => config.max_queries = try!(integer(&path, value, 1, 64 * 1024)) as usize,
+            _ => return invalid(&path, "unknown key")
+        }
+    }
+    Ok(())
+}
+
+fn tcp_section(section: &str, value: &toml::Value, config: &mut server::Config) -> Result<(), Error> {
+    for (key, value) in try!(table(section, value)).iter() {
+        let path = format!("{}.{}", section, key);
+        match &key[..] {
+            "idle_timeout_ms" => config.tcp_idle_ms = try!(integer(&path, value, 1, 60 * 60 * 1000)) as u64,
+            "max_connections" => config.max_connections = try!(integer(&path, value, 1, 64 * 1024)) as usize,
+            _ => return invalid(&path, "unknown key")
+        }
+    }
+    Ok(())
+}
+
+fn cache_section(section: &str, value: &toml::Value, config: &mut server::Config) -> Result<(), Error> {
+    const MAX_TTL: i64 = 0x7fffffff;
+    let options = &mut config.cache;
+
+    for (key, value) in try!(table(section, value)).iter() {
+        let path = format!("{}.{}", section, key);
+        match &key[..] {
+            "file" => config.cache_file = Some(PathBuf::from(try!(string(&path, value)))),
+            "min_ttl" => options.min_ttl = try!(integer(&path, value, 0, MAX_TTL)) as i32,
+            "max_ttl" => options.max_ttl = try!(integer(&path, value, 0, MAX_TTL)) as i32,
+            "max_negative_ttl" => options.max_negative_ttl = try!(integer(&path, value, 0, MAX_TTL)) as i32,
+            "stale_window" => options.stale_window = try!(integer(&path, value, 0, MAX_TTL)) as i32,
+            "prefetch_hits" => options.prefetch_hits = try!(integer(&path, value, 0, 0xffffffff)) as u32,
+            "max_entries" => options.max_entries = try!(integer(&path, value, 1, i64::max_value())) as usize,
+            "max_bytes" => options.max_bytes = try!(integer(&path, value, 1, i64::max_value())) as usize,
+            _ => return invalid(&path, "unknown key")
+        }
+    }
+    Ok(())
+}
+
+fn table<'a>(key: &str, value: &'a toml::Value) -> Result<&'a toml::Table, Error> {
+    match value.as_table() {
+        Some(t) => Ok(t),
+        None => invalid(key, &format!("expected a section, found {}", value.type_str()))
+    }
+}
+
+fn string(key: &str, value: &toml::Value) -> Result<String, Error> {
+    match value.as_str() {
+        Some(s) => Ok(s.to_owned()),
+        None => invalid(key, &format!("expected a string, found {}", value.type_str()))
+    }
+}
+
+fn strings(key: &str, value: &toml::Value) -> Result<Vec<String>, Error> {
+    match value.as_slice() {
+        Some(values) => values.iter().enumerate()
+            .map(|(i, v)| string(&format!("{}[{}]", key, i), v))
+            .collect(),
+        None => invalid(key, &format!("expected an array of strings, found {}", value.type_str()))
+    }
+}
+
+fn integer(key: &str, value: &toml::Value, min: i64, max: i64) -> Result<i64, Error> {
+    match value.as_integer() {
+        Some(i) if i >= min && i <= max => Ok(i),
+        Some(_) => invalid(key, &format!("expected {} to {}", min, max)),
+        None => invalid(key, &format!("expected an integer, found {}", value.type_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn full() {
+        let config = Config::parse(r#"
+            listen = ["127.0.0.1:53", "tcp:[::1]:53"]
+            control = "127.0.0.1:5300"
+            user = "nobody"
+            group = "nogroup"
+
+            [upstream]
+            servers = ["8.8.8.8", "[2001:4860:4860::8888]:5353"]
+            timeout_ms = 5000
+            max_queries = 64
+
+            [tcp]
+            idle_timeout_ms = 2000
+
+            [cache]
+            file = "/tmp/pdnsd.cache"
+            min_ttl = 60
+        "#).unwrap();
+
+        assert_eq!(config.listen, vec!["127.0.0.1:53".to_owned(), "tcp:[::1]:53".to_owned()]);
+        assert_eq!(config.control, Some("127.0.0.1:5300".parse().unwrap()));
+        assert_eq!(config.user, Some("nobody".to_owned()));
+        assert_eq!(config.group, Some("nogroup".to_owned()));
+        assert_eq!(config.server.upstreams, vec!["8.8.8.8:53".parse().unwrap(),
+                                                 "[2001:4860:4860::8888]:5353".parse().unwrap()]);
+        assert_eq!(config.server.query_timeout_ms, 5000);
+        assert_eq!(config.server.max_queries, 64);
+        assert_eq!(config.server.tcp_idle_ms, 2000);
+        assert_eq!(config.server.cache_file, Some(PathBuf::from("/tmp/pdnsd.cache")));
+        assert_eq!(config.server.cache.min_ttl, 60);
+
+        // everything else keeps its default
+        let default = Config::default();
+        assert_eq!(config.server.fallback_ms, default.server.fallback_ms);
+        assert_eq!(config.server.cache.max_ttl, default.server.cache.max_ttl);
+
+        let empty = Config::parse("").unwrap();
+        assert_eq!(empty.listen, default.listen);
+        assert_eq!(empty.server.upstreams, default.server.upstreams);
+    }
+
+    fn error(s: &str) -> String {
+        format!("{}", Config::parse(s).and_then(|c| c.check(&[])).unwrap_err())
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(error("listen = [\"127.0.0.1:53\"\nuser = 1"), "line 2, column 1: expected `]`, but found `u`");
+        assert_eq!(error("users = \"nobody\""), "users: unknown key");
+        assert_eq!(error("user = 1"), "user: expected a string, found integer");
+        assert_eq!(error("listen = \"127.0.0.1:53\""), "listen: expected an array of strings, found string");
+        assert_eq!(error("listen = [\"127.0.0.1:53\", \"udp:localhost\"]"),
+                   "listen[1]: expected an address like 127.0.0.1:53, udp:[::1]:53");
+        assert_eq!(error("control = \"0.0.0.0:5300\""), "control: expected a loopback address like 127.0.0.1:5300");
+        assert_eq!(error("upstream = 1"), "upstream: expected a section, found integer");
+        assert_eq!(error("[upstream]\nservers = [\"8.8.8.8\", \"dns.google\"]"),
+                   "upstream.servers[1]: expected an address like 8.8.8.8 or [2001:4860:4860::8888]:53");
+        assert_eq!(error("[upstream]\nservers = []"), "upstream.servers: expected 1 to 16 servers");
+        assert_eq!(error("[upstream]\ntimeout_ms = 0"), "upstream.timeout_ms: expected 1 to 60000");
+        assert_eq!(error("[upstream]\ntimeout = 10"), "upstream.timeout: unknown key");
+        assert_eq!(error("[tcp]\nmax_connections = \"10\""), "tcp.max_connections: expected an integer, found string");
+        assert_eq!(error("[cache]\nmin_ttl = -1"), "cache.min_ttl: expected 0 to 2147483647");
+        assert_eq!(error("[cache]\nmin_ttl = 600\nmax_ttl = 60"), "cache.min_ttl: greater than cache.max_ttl");
+        assert_eq!(error("user = \"nobody\""), "user: needs group too");
+        assert_eq!(error("group = \"nogroup\""), "group: needs user too");
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn override_error(options: &[(&str, Vec<String>)]) -> String {
+        format!("{}", Config::default().override_with(options).and_then(|c| c.check(options)).unwrap_err())
+    }
+
+    #[test]
+    fn command_line() {
+        let file = Config::parse("[cache]\nmax_ttl = 600\n[upstream]\nservers = [\"8.8.8.8\"]").unwrap();
+        let config = file.override_with(&[
+            ("min-ttl", strings(&["60"])),
+            ("upstream", strings(&["1.1.1.1", "[::1]:5353"]))
+        ]).unwrap();
+        assert_eq!(config.server.cache.min_ttl, 60);
+        assert_eq!(config.server.cache.max_ttl, 600);
+        assert_eq!(config.server.upstreams, vec!["1.1.1.1:53".parse().unwrap(), "[::1]:5353".parse().unwrap()]);
+
+        assert_eq!(override_error(&[("min-ttl", strings(&["a minute"]))]), "--min-ttl: expected an integer");
+        assert_eq!(override_error(&[("cache-bytes", strings(&["0"]))]), "--cache-bytes: expected 1 to 9223372036854775807");
+        assert_eq!(override_error(&[("min-ttl", strings(&["600"])), ("max-ttl", strings(&["60"]))]),
+                   "--min-ttl: greater than --max-ttl");
+        assert_eq!(override_error(&[("min-ttl", strings(&["100000"]))]), "--min-ttl: greater than cache.max_ttl");
+        assert_eq!(override_error(&[("upstream", strings(&["8.8.8.8", "dns.google"]))]),
+                   "--upstream: expected an address like 8.8.8.8 or [2001:4860:4860::8888]:53");
+        assert_eq!(override_error(&[("control", strings(&["0.0.0.0:5300"]))]),
+                   "--control: expected a loopback address like 127.0.0.1:5300");
+        assert_eq!(override_error(&[("user", strings(&["nobody"]))]), "--user: needs group too");
+
+        // the file and the command line are checked together
+        let options = [("group", strings(&["nogroup"])), ("max-ttl", strings(&["7200"]))];
+        let file = Config::parse("user = \"nobody\"\n[cache]\nmin_ttl = 3600\nmax_ttl = 60").unwrap();
+        let config = file.override_with(&options).unwrap();
+        assert!(config.check(&options).is_ok());
+        assert_eq!(config.user, Some("nobody".to_owned()));
+        assert_eq!(config.group, Some("nogroup".to_owned()));
+        assert_eq!(config.server.cache.max_ttl, 7200);
+    }
+}
@@ -0,0 +1,232 @@
+use mio::{Token, EventSet, PollOpt, Handler, EventLoop, Timeout, TryRead, TryWrite};
+use mio::tcp::TcpStream;
+use std::net::SocketAddr;
+use byteorder::{ByteOrder, BigEndian};
+use std::io;
+use std::fmt;
+
+/// Queries a client may have in flight on one connection before we stop
+/// reading from it.
+pub const MAX_PENDING_QUERIES: usize = 16;
+/// Bytes read ahead of the queries being answered, room for the largest
+/// message plus its length.
+const MAX_BUFFERED: usize = 0x10001;
+
+/// A client connection speaking DNS over TCP, every message is prefixed
+/// with its length as two bytes (RFC 1035 4.2.2). Clients may send several
+/// queries without waiting, their answers go back in whatever order they
+/// are ready in (RFC 7766 6.2.1.1).
+pub struct Connection {
+    token: Token,
+    socket: TcpStream,
+    addr: SocketAddr,
+    rx: Vec<u8>,
+    tx: Vec<u8>,
+    /// queries read from the connection and not answered yet
+    queries: Vec<Token>,
+    /// the client closed its side, only answers are left to write
+    eof: bool,
+    timeout: Option<Timeout>
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Connection[{:?} from {}]", self.token, self.addr)
+    }
+}
+
+impl Connection {
+    pub fn new(t: Token, socket: TcpStream, addr: SocketAddr) -> Connection {
+        Connection {
+            token: t,
+            socket: socket,
+            addr: addr,
+            rx: Vec::new(),
+            tx: Vec::new(),
+            queries: Vec::new(),
+            eof: false,
+            timeout: None
+        }
+    }
+
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    pub fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    pub fn get_addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+
+    pub fn queries(&self) -> &[Token] {
+        &self.queries
+    }
+
+    pub fn add_query(&mut self, t: Token) {
+        self.queries.push(t);
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.queries.is_empty() && self.tx.is_empty()
+    }
+
+    pub fn set_timeout(&mut self, t: Timeout) {
+        self.timeout = Some(t);
+    }
+
+    pub fn take_timeout(&mut self) -> Option<Timeout> {
+        self.timeout.take()
+    }
+
+    /// Done once the client went away and everything it asked is answered.
+    pub fn is_finished(&self) -> bool {
+        self.eof && self.is_idle() && !self.has_request()
+    }
+
+    pub fn forget_query(&mut self, t: Token) {
+        self.queries.retain(|q| *q != t);
+    }
+
+    fn has_room(&self) -> bool {
+        self.queries.len() < MAX_PENDING_QUERIES
+    }
+
+    /// A complete request is buffered and there's room to start on it.
+    pub fn has_request(&self) -> bool {
+        self.has_room() && self.rx.len() >= 2 &&
+            self.rx.len() >= BigEndian::read_u16(&self.rx[..2]) as usize + 2
+    }
+
+    /// Reads whatever the socket has, nothing more is read while the
+    /// client has too many queries pending.
+    pub fn read(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+
+        while !self.eof && self.has_room() && self.rx.len() < MAX_BUFFERED {
+            match try!(self.socket.try_read(&mut buf)) {
+                Some(0) => self.eof = true,
+                Some(size) => self.rx.extend_from_slice(&buf[..size]),
+                None => break
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the next request read, if there's room for another query.
+    pub fn next_request(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if !self.has_room() {
+            return Ok(None)
+        }
+        read_frame(&mut self.rx)
+    }
+
+    /// Queues the answer to query `t` to be written out.
+    pub fn respond(&mut self, t: Token, bytes: &[u8]) -> io::Result<()> {
+        self.forget_query(t);
+        write_frame(&mut self.tx, bytes)
+    }
+
+    /// Queues an answer to a request that never became a query.
+    pub fn reply(&mut self, bytes: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.tx, bytes)
+    }
+
+    /// Writes as much of the queued answers as the socket takes.
+    pub fn write(&mut self) -> io::Result<()> {
+        while !self.tx.is_empty() {
+            match try!(self.socket.try_write(&self.tx)) {
+                Some(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+                Some(size) => { self.tx.drain(..size); },
+                None => break
+            }
+        }
+        Ok(())
+    }
+
+    pub fn reregister<H: Handler>(&self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+        let (event_set, poll_opt) = self.event_set_poll_opts();
+        event_loop.reregister(&self.socket, self.token, event_set, poll_opt)
+    }
+
+    pub fn register<H: Handler>(&self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+        let (event_set, poll_opt) = self.event_set_poll_opts();
+        event_loop.register(&self.socket, self.token, event_set, poll_opt)
+    }
+
+    fn event_set_poll_opts(&self) -> (EventSet, PollOpt) {
+        let mut event_set = EventSet::hup() | EventSet::error();
+
+        if !self.eof && self.has_room() && self.rx.len() < MAX_BUFFERED {
+            event_set = event_set | EventSet::readable();
+        }
+        if !self.tx.is_empty() {
+            event_set = event_set | EventSet::writable();
+        }
+        (event_set, PollOpt::edge() | PollOpt::oneshot())
+    }
+}
+
+/// Takes the first complete message off the front of `buf`.
+fn read_frame(buf: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if buf.len() < 2 {
+        return Ok(None)
+    }
+
+    let len = BigEndian::read_u16(&buf[..2]) as usize;
+    if len == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty message"))
+    }
+    if buf.len() < len + 2 {
+        return Ok(None)
+    }
+
+    let message = buf[2..len + 2].to_vec();
+    buf.drain(..len + 2);
+    Ok(Some(message))
+}
+
+fn write_frame(buf: &mut Vec<u8>, message: &[u8]) -> io::Result<()> {
+    if message.len() > 0xffff {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too long"))
+    }
+
+    let mut len = [0u8; 2];
+    BigEndian::write_u16(&mut len, message.len() as u16);
+    buf.extend_from_slice(&len);
+    buf.extend_from_slice(message);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use self::super::{read_frame, write_frame};
+
+    #[test]
+    fn framing() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"first").unwrap();
+        write_frame(&mut buf, b"second").unwrap();
+        assert_eq!(&buf[..7], b"\x00\x05first");
+
+        // the second message arrives in pieces
+        let tail = buf.split_off(10);
+        assert_eq!(read_frame(&mut buf).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_frame(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&tail);
+        assert_eq!(read_frame(&mut buf).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(read_frame(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+
+        let mut short = vec![0u8];
+        assert_eq!(read_frame(&mut short).unwrap(), None);
+
+        let mut empty = vec![0u8, 0];
+        assert!(read_frame(&mut empty).is_err());
+
+        assert!(write_frame(&mut Vec::new(), &vec![0u8; 0x10000]).is_err());
+    }
+}
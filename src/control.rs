@@ -0,0 +1,156 @@
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::io::{self, BufRead, BufReader, Write};
+use std::thread;
+use std::time::Duration;
+use mio;
+use chan;
+use server::ServerEvent;
+use cache::{EntryInfo, Stats, TYPE_NAME};
+use dns::{QuestionClass, QuestionType};
+
+/// Seconds a control client may sit without sending a line.
+const CLIENT_IDLE_SECS: u64 = 60;
+
+/// Starts a thread serving the line based control interface on `addr`,
+/// each line is one of:
+///
+/// ```text
+/// list [NAME]        cache entries at or below NAME, all of them without one,
+///                    with their records in zone file format
+/// flush NAME         every type cached for NAME
+/// flush-tree NAME    NAME and everything below it
+/// flush-all          the whole cache
+/// stats              cache counters along with its entries and memory use
+/// ```
+///
+/// There's no authentication, anyone who can connect can read and flush the
+/// cache, so only loopback addresses are accepted.
+pub fn run_control(addr: &SocketAddr, server: mio::Sender<ServerEvent>) -> io::Result<thread::JoinHandle<()>> {
+    if !addr.ip().is_loopback() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "control interface must listen on a loopback address"))
+    }
+
+    let listener = try!(TcpListener::bind(addr));
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(s) => {
+                    // a client that stalls mustn't keep the others out
+                    let server = server.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_client(s, &server) {
+                            warn!("control connection error: {:?}", e);
+                        }
+                    });
+                },
+                Err(e) => warn!("control accept error: {:?}", e)
+            }
+        }
+    }))
+}
+
+/// Hands an event to the event loop and waits for its reply.
+fn request<T, F>(server: &mio::Sender<ServerEvent>, event: F) -> Option<T>
+    where F: FnOnce(chan::Sender<T>) -> ServerEvent
+{
+    let (tx, rx) = chan::sync(1);
+
+    if let Err(e) = server.send(event(tx)) {
+        error!("error in control event send: {:?}", e);
+        return None
+    }
+
+    rx.recv()
+}
+
+fn format_entries(entries: &[EntryInfo]) -> String {
+    let mut s = String::new();
+
+    for e in entries.iter() {
+        let status = match e.rcode {
+            Some(3) => "NXDOMAIN".to_owned(),
+            Some(_) => "NODATA".to_owned(),
+            None => format!("{} records", e.records.len())
+        };
+        let r_type = match e.r_type {
+            TYPE_NAME => "*".to_owned(),
+            t => QuestionType::new(t).to_string()
+        };
+        s.push_str(&format!("{}\t{}\t{}\t{}\t{}\n", e.name, e.ttl, QuestionClass::new(e.r_class),
+                            r_type, status));
+        for r in e.records.iter() {
+            s.push_str(&format!("\t{}\n", r));
+        }
+    }
+
+    s.push_str(&format!("{} entries\n", entries.len()));
+    s
+}
+
+fn format_stats(stats: &Stats, entries: usize, bytes: usize) -> String {
+    format!("entries {}\nbytes {}\nhits {}\nmisses {}\nstale_hits {}\nprefetches {}\n\
+             insertions {}\nexpirations {}\nevictions {}\n",
+            entries, bytes, stats.hits, stats.misses, stats.stale_hits, stats.prefetches,
+            stats.insertions, stats.expirations, stats.evictions)
+}
+
+fn handle_client(stream: TcpStream, server: &mio::Sender<ServerEvent>) -> io::Result<()> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(CLIENT_IDLE_SECS))));
+    let mut out = try!(stream.try_clone());
+
+    for line in BufReader::new(stream).lines() {
+        let line = try!(line);
+        let mut words = line.split_whitespace();
+
+        let reply = match (words.next(), words.next()) {
+            (Some("list"), name) => {
+                let name = name.unwrap_or("").to_owned();
+                request(server, |tx| ServerEvent::ListCache(name, tx)).map(|e| format_entries(&e))
+            },
+            (Some("flush"), Some(name)) => {
+                let name = name.to_owned();
+                request(server, |tx| ServerEvent::FlushName(name, tx)).map(|n| format!("{} entries flushed\n", n))
+            },
+            (Some("flush-tree"), Some(name)) => {
+                let name = name.to_owned();
+                request(server, |tx| ServerEvent::FlushTree(name, tx)).map(|n| format!("{} entries flushed\n", n))
+            },
+            (Some("flush-all"), None) => {
+                request(server, |tx| ServerEvent::FlushAll(tx)).map(|n| format!("{} entries flushed\n", n))
+            },
+            (Some("stats"), None) => {
+                request(server, |tx| ServerEvent::CacheStats(tx)).map(|(s, entries, bytes)| format_stats(&s, entries, bytes))
+            },
+            (None, _) => continue,
+            _ => Some(format!("unknown command: {}\n", line.trim()))
+        };
+
+        match reply {
+            Some(r) => try!(out.write_all(r.as_bytes())),
+            None => return Err(io::Error::new(io::ErrorKind::Other, "event loop went away"))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use server;
+
+    #[test]
+    fn stats() {
+        let listeners = server::Listener::bind("udp:127.0.0.1:0").unwrap();
+        let (thr, sender, _) = server::run_server(listeners, server::Config::default());
+
+        let (stats, entries, bytes) = request(&sender, |tx| ServerEvent::CacheStats(tx)).unwrap();
+        assert_eq!((0, 0, 0), (stats.hits, entries, bytes));
+        assert_eq!(format_stats(&stats, entries, bytes),
+                   "entries 0\nbytes 0\nhits 0\nmisses 0\nstale_hits 0\nprefetches 0\n\
+                    insertions 0\nexpirations 0\nevictions 0\n");
+
+        sender.send(ServerEvent::Quit).unwrap();
+        thr.join().unwrap();
+    }
+}
@@ -1,5 +1,7 @@
-use mio::{Token, EventSet, PollOpt, Handler, EventLoop};
+use mio::{Token, EventSet, PollOpt, Handler, EventLoop, TryRead, TryWrite};
 use mio::udp::UdpSocket;
+use mio::tcp::TcpStream;
+use byteorder::{ByteOrder, BigEndian};
 use std::net::SocketAddr;
 use std::io::prelude::*;
 use errors::*;
@@ -13,7 +15,43 @@ pub struct Datagram {
     socket_addr: SocketAddr,
     socket: UdpSocket,
     buf: ByteBuf,
-    state: State
+    state: State,
+    /// set once the question is asked again over TCP
+    stream: Option<Stream>
+}
+
+/// The TCP connection a question is asked again over when the answer
+/// didn't fit a datagram, both ways the message is prefixed with its
+/// length.
+#[derive (Debug)]
+struct Stream {
+    socket: TcpStream,
+    registered: bool,
+    question_len: usize,
+    tx: Vec<u8>,
+    rx: Vec<u8>
+}
+
+impl Stream {
+    /// The whole answer once it's been read.
+    fn answer(&self) -> Option<&[u8]> {
+        if self.rx.len() < 2 {
+            return None
+        }
+        let len = BigEndian::read_u16(&self.rx[..2]) as usize;
+        if self.rx.len() < len + 2 {
+            return None
+        }
+        Some(&self.rx[2..len + 2])
+    }
+}
+
+/// A socket of the right family to send to `remote` from.
+pub fn socket_for(remote: &SocketAddr) -> io::Result<UdpSocket> {
+    match *remote {
+        SocketAddr::V4(..) => UdpSocket::v4(),
+        SocketAddr::V6(..) => UdpSocket::v6()
+    }
 }
 
 pub enum EventResponse {
@@ -30,14 +68,15 @@ pub enum State {
 }
 
 impl Datagram {
-    pub fn new(t: Token, qt: Token, remote: SocketAddr) -> Datagram {
+    pub fn new(t: Token, qt: Token, remote: SocketAddr, socket: UdpSocket) -> Datagram {
         Datagram{
             token: t,
             query_token: qt,
             socket_addr: remote,
-            socket: UdpSocket::v4().unwrap(),
+            socket: socket,
             buf: ByteBuf::new(),
-            state: State::Idle
+            state: State::Idle,
+            stream: None
         }
     }
 
@@ -91,10 +130,79 @@ impl Datagram {
     }
 
     pub fn get_ref(&self) -> &[u8] {
-        self.buf.bytes()
+        match self.stream {
+            Some(ref stream) => stream.answer().unwrap_or(&[]),
+            None => self.buf.bytes()
+        }
+    }
+
+    pub fn is_tcp(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Asks the question in `bytes` again over TCP, the connection is made
+    /// and registered in place of the datagram socket on `reregister`.
+    pub fn retry_tcp(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.len() > 0xffff {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "message too long"))
+        }
+
+        let mut tx = vec![0u8; 2];
+        BigEndian::write_u16(&mut tx, bytes.len() as u16);
+        tx.extend_from_slice(bytes);
+
+        self.stream = Some(Stream {
+            socket: try!(TcpStream::connect(&self.socket_addr)),
+            registered: false,
+            question_len: bytes.len(),
+            tx: tx,
+            rx: Vec::new()
+        });
+        self.state = State::Tx;
+        Ok(())
+    }
+
+    fn stream_event(&mut self, events: EventSet) -> Result<EventResponse, Error> {
+        let stream = try!(self.stream.as_mut().ok_or("no stream"));
+
+        if events.is_error() {
+            try!(stream.socket.take_socket_error());
+        }
+
+        match self.state {
+            State::Tx if events.is_writable() => {
+                while !stream.tx.is_empty() {
+                    match try!(stream.socket.try_write(&stream.tx)) {
+                        Some(0) => return Err(Error::String("connection closed")),
+                        Some(size) => { stream.tx.drain(..size); },
+                        None => return Ok(EventResponse::Tx(None))
+                    }
+                }
+                Ok(EventResponse::Tx(Some(stream.question_len)))
+            },
+            State::Rx if events.is_readable() || events.is_hup() => {
+                let mut buf = [0u8; 4096];
+
+                while stream.answer().is_none() {
+                    match try!(stream.socket.try_read(&mut buf)) {
+                        Some(0) => return Err(Error::String("connection closed")),
+                        Some(size) => stream.rx.extend_from_slice(&buf[..size]),
+                        None => return Ok(EventResponse::Rx(None))
+                    }
+                }
+                let size = stream.rx.len() - 2;
+                Ok(EventResponse::Rx(Some((size, self.socket_addr))))
+            },
+            State::Idle => Ok(EventResponse::Nothing),
+            _ => Err(Error::String("invalid state"))
+        }
     }
 
     pub fn event(&mut self, events: EventSet) -> Result<EventResponse, Error> {
+        if self.stream.is_some() {
+            return self.stream_event(events)
+        }
+
         match self.state {
             State::Tx => {
                 // if the buf is readable we are TX'ing
@@ -132,9 +240,28 @@ impl Datagram {
         }
     }
 
-    pub fn reregister<H: Handler>(&self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+    pub fn reregister<H: Handler>(&mut self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
         let (event_set, poll_opt) = self.event_set_poll_opts();
-        event_loop.reregister(&self.socket, self.token, event_set, poll_opt)
+
+        match self.stream {
+            Some(ref mut stream) if !stream.registered => {
+                // the stream takes over the token from the datagram socket
+                try!(event_loop.deregister(&self.socket));
+                try!(event_loop.register(&stream.socket, self.token, event_set, poll_opt));
+                stream.registered = true;
+                Ok(())
+            },
+            Some(ref stream) => event_loop.reregister(&stream.socket, self.token, event_set, poll_opt),
+            None => event_loop.reregister(&self.socket, self.token, event_set, poll_opt)
+        }
+    }
+
+    pub fn deregister<H: Handler>(&self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
+        match self.stream {
+            Some(ref stream) if stream.registered => event_loop.deregister(&stream.socket),
+            Some(_) => Ok(()),
+            None => event_loop.deregister(&self.socket)
+        }
     }
 
     pub fn register<H: Handler>(&self, event_loop: &mut EventLoop<H>) -> io::Result<()> {
@@ -148,6 +275,9 @@ impl Datagram {
             State::Tx => {
                 (EventSet::writable(), PollOpt::edge() | PollOpt::oneshot())
             },
+            State::Rx if self.stream.is_some() => {
+                (EventSet::readable() | EventSet::hup(), PollOpt::edge() | PollOpt::oneshot())
+            },
             State::Rx => {
                 (EventSet::readable(), PollOpt::edge() | PollOpt::oneshot())
             },
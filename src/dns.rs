@@ -1,72 +1,149 @@
-use byteorder::{BigEndian, ReadBytesExt};
-use std::io::{Cursor};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Cursor, Write};
+use std::collections::HashMap;
 use byteorder;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use arrayvec::*;
 use std::borrow::Cow;
+use std::mem;
+use std::cmp;
 use smallvec::SmallVec;
 
-#[derive (Debug, PartialEq, Copy, Clone)]
-#[allow(non_camel_case_types, dead_code)]
-pub enum QuestionType {
-    A           = 0x01,
-    NS          = 0x02,
-    MD          = 0x03, // obsolete
-    MF          = 0x04, // obsolete
-    CNAME       = 0x05,
-    SOA         = 0x06,
-    MB          = 0x07,
-    MG          = 0x08,
-    MR          = 0x09,
-    NULL        = 0x0a,
-    WKS         = 0x0b,
-    PTR         = 0x0c,
-    HINFO       = 0x0d,
-    MINFO       = 0x0e,
-    MX          = 0x0f,
-    TXT         = 0x10,
-    // only valid for questions
-    SRV         = 0x21,
-    IXFR        = 0xfb,
-    AXFR        = 0xfc,
-    ALL         = 0xff
-}
-
-impl QuestionType {
-    fn new(i: u16) -> Option<QuestionType> {
-        use self::QuestionType::*;
-        Some(match i {
-            0x01 => A,
-            0x02 => NS,
-            0x05 => CNAME,
-            0x0c => PTR,
-            0x0f => MX,
-            0x21 => SRV,
-            0xfb => IXFR,
-            0xfc => AXFR,
-            0xff => ALL,
-            _ => return None
-        })
+/// Generates a type code enum along with the conversions from and to its
+/// numeric value, codes without a variant are kept as `Unknown`.
+macro_rules! codes {
+    ($(#[$attr:meta])* pub enum $name:ident { $($variant:ident = $code:expr),* }) => {
+        $(#[$attr])*
+        #[derive (Debug, PartialEq, Eq, Hash, Copy, Clone)]
+        #[allow(non_camel_case_types, dead_code)]
+        pub enum $name {
+            $($variant,)*
+            Unknown(u16)
+        }
+
+        impl $name {
+            pub fn new(i: u16) -> $name {
+                match i {
+                    $($code => $name::$variant,)*
+                    _ => $name::Unknown(i)
+                }
+            }
+
+            pub fn code(&self) -> u16 {
+                match *self {
+                    $($name::$variant => $code,)*
+                    $name::Unknown(i) => i
+                }
+            }
+
+            /// The variant's name, `None` for unknown codes.
+            pub fn mnemonic(&self) -> Option<&'static str> {
+                match *self {
+                    $($name::$variant => Some(stringify!($variant)),)*
+                    $name::Unknown(_) => None
+                }
+            }
+
+            /// Looks a variant up by name, ignoring case.
+            pub fn from_mnemonic(s: &str) -> Option<$name> {
+                $(if s.eq_ignore_ascii_case(stringify!($variant)) {
+                    return Some($name::$variant)
+                })*
+                None
+            }
+        }
     }
 }
 
-#[derive (Debug, PartialEq, Copy, Clone)]
-pub enum QuestionClass {
-    IN = 0x01
+codes! {
+    pub enum QuestionType {
+        A           = 0x01,
+        NS          = 0x02,
+        MD          = 0x03, // obsolete
+        MF          = 0x04, // obsolete
+        CNAME       = 0x05,
+        SOA         = 0x06,
+        MB          = 0x07,
+        MG          = 0x08,
+        MR          = 0x09,
+        NULL        = 0x0a,
+        WKS         = 0x0b,
+        PTR         = 0x0c,
+        HINFO       = 0x0d,
+        MINFO       = 0x0e,
+        MX          = 0x0f,
+        TXT         = 0x10,
+        AAAA        = 0x1c,
+        SRV         = 0x21,
+        NAPTR       = 0x23,
+        DS          = 0x2b,
+        RRSIG       = 0x2e,
+        NSEC        = 0x2f,
+        DNSKEY      = 0x30,
+        NSEC3       = 0x32,
+        HTTPS       = 0x41,
+        CAA         = 0x101,
+        // only valid for questions
+        IXFR        = 0xfb,
+        AXFR        = 0xfc,
+        ALL         = 0xff
+    }
 }
 
-pub const LABEL_MAX_LENGTH: usize = 63;
-pub const NAMES_MAX_LENGTH: usize = 255;
+codes! {
+    pub enum Opcode {
+        Query       = 0,
+        IQuery      = 1, // obsolete
+        Status      = 2,
+        Notify      = 4,
+        Update      = 5
+    }
+}
 
-impl QuestionClass {
-    fn new(i: u16) -> Option<QuestionClass> {
-        match i {
-            0x01 => Some(QuestionClass::IN),
-            _ => None
-        }
+codes! {
+    /// Values above 15 need an OPT record to be sent (RFC 6891).
+    pub enum Rcode {
+        NoError     = 0,
+        FormErr     = 1,
+        ServFail    = 2,
+        NXDomain    = 3,
+        NotImp      = 4,
+        Refused     = 5,
+        YXDomain    = 6,
+        YXRRSet     = 7,
+        NXRRSet     = 8,
+        NotAuth     = 9,
+        NotZone     = 10,
+        BadVers     = 16
+    }
+}
+
+codes! {
+    pub enum QuestionClass {
+        IN          = 0x01,
+        CH          = 0x03,
+        HS          = 0x04,
+        // only valid for questions
+        ANY         = 0xff
     }
 }
 
+pub const LABEL_MAX_LENGTH: usize = 63;
+pub const NAMES_MAX_LENGTH: usize = 255;
+/// The largest message a client without EDNS accepts over UDP.
+pub const MIN_PAYLOAD_SIZE: u16 = 512;
+/// The UDP payload size we advertise, small enough to avoid fragmentation.
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+const TYPE_OPT: u16 = 0x29;
+
+const FLAG_QR: u16 = 1 << 15;
+const FLAG_AA: u16 = 1 << 10;
+const FLAG_TC: u16 = 1 << 9;
+const FLAG_RD: u16 = 1 << 8;
+const FLAG_RA: u16 = 1 << 7;
+const FLAG_AD: u16 = 1 << 5;
+const FLAG_CD: u16 = 1 << 4;
+
 pub type Name = ArrayVec<[u8;256]>;
 
 #[derive (Debug, Clone)]
@@ -77,9 +154,29 @@ pub struct Question {
 }
 
 impl Question {
+    pub fn new(name: Name, q_type: QuestionType, q_class: QuestionClass) -> Question {
+        Question {
+            q_name: name,
+            q_type: q_type,
+            q_class: q_class
+        }
+    }
+
+    pub fn raw_name(&self) -> &Name {
+        &self.q_name
+    }
+
     pub fn name(&self) -> Cow<str> {
         String::from_utf8_lossy(&self.q_name)
     }
+
+    pub fn q_type(&self) -> QuestionType {
+        self.q_type
+    }
+
+    pub fn q_class(&self) -> QuestionClass {
+        self.q_class
+    }
 }
 
 #[derive (Debug, Clone)]
@@ -95,14 +192,164 @@ impl ResourceRecord {
     pub fn name(&self) -> Cow<str> {
         String::from_utf8_lossy(&self.r_name)
     }
+
+    /// Encodes the record on its own, names are written uncompressed.
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let mut writer = Writer::uncompressed();
+        try!(writer.write_resource_record(self));
+        writer.finish(w)
+    }
+
+    /// Decodes a record from the start of `b` as written by `write`,
+    /// returning it along with the number of bytes it took up.
+    pub fn read(b: &[u8]) -> Result<(ResourceRecord, usize), Error> {
+        let mut p = Parser::new(b);
+        let record = try!(p.parse_resource_record());
+
+        Ok((record, p.cursor.position() as usize))
+    }
+
+    /// Whether the RDATA can be copied into another message as is, names
+    /// inside the RDATA of these types may be compressed against the
+    /// message they arrived in (RFC 3597 section 4).
+    pub fn is_self_contained(&self) -> bool {
+        match self.r_data {
+            ResourceData::Bytes(_) => match self.r_type {
+                0x03 | 0x04 | 0x07...0x09 | 0x0e | 0x11 | 0x12 | 0x15 | 0x18 |
+                0x1a | 0x1e => false,
+                _ => true
+            },
+            _ => true
+        }
+    }
 }
 
 #[derive (Debug, Clone, PartialEq)]
 pub enum ResourceData {
     A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(Name),
+    CNAME(Name),
+    PTR(Name),
+    MX {
+        preference: u16,
+        exchange: Name
+    },
+    SOA {
+        mname: Name,
+        rname: Name,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32
+    },
+    /// one or more character strings
+    TXT(Vec<Vec<u8>>),
+    HINFO {
+        cpu: Vec<u8>,
+        os: Vec<u8>
+    },
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Name
+    },
+    NAPTR {
+        order: u16,
+        preference: u16,
+        flags: Vec<u8>,
+        services: Vec<u8>,
+        regexp: Vec<u8>,
+        replacement: Name
+    },
+    CAA {
+        flags: u8,
+        tag: Vec<u8>,
+        value: Vec<u8>
+    },
+    DS {
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>
+    },
+    DNSKEY {
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>
+    },
+    RRSIG {
+        type_covered: u16,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer_name: Name,
+        signature: Vec<u8>
+    },
+    NSEC {
+        next_domain: Name,
+        type_bitmaps: Vec<u8>
+    },
+    NSEC3 {
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed_owner: Vec<u8>,
+        type_bitmaps: Vec<u8>
+    },
+    /// RDATA of types not decoded above, as it arrived
     Bytes(Vec<u8>)
 }
 
+impl ResourceData {
+    /// Decodes the RDATA of a record of type `r_type` on its own, names in
+    /// it can't be compressed.
+    pub fn decode(r_type: u16, rdata: &[u8]) -> Result<ResourceData, Error> {
+        if rdata.len() > 0xffff {
+            return Err(Error::Parse)
+        }
+
+        let mut p = Parser::new(rdata);
+        let data = try!(p.read_rdata(r_type, rdata.len() as u16));
+        if p.cursor.position() as usize != rdata.len() {
+            return Err(Error::Parse)
+        }
+        Ok(data)
+    }
+
+    /// The RDATA in wire format, uncompressed.
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = Writer::uncompressed();
+        try!(writer.write_rdata(self));
+        Ok(writer.buf)
+    }
+
+    /// The number of bytes held on the heap.
+    pub fn heap_size(&self) -> usize {
+        use self::ResourceData::*;
+        match *self {
+            TXT(ref strings) => strings.iter().map(|s| s.capacity() + mem::size_of::<Vec<u8>>()).fold(0, |a, b| a + b),
+            HINFO { ref cpu, ref os } => cpu.capacity() + os.capacity(),
+            NAPTR { ref flags, ref services, ref regexp, .. } => flags.capacity() + services.capacity() + regexp.capacity(),
+            CAA { ref tag, ref value, .. } => tag.capacity() + value.capacity(),
+            DS { ref digest, .. } => digest.capacity(),
+            DNSKEY { ref public_key, .. } => public_key.capacity(),
+            RRSIG { ref signature, .. } => signature.capacity(),
+            NSEC { ref type_bitmaps, .. } => type_bitmaps.capacity(),
+            NSEC3 { ref salt, ref next_hashed_owner, ref type_bitmaps, .. } => salt.capacity() + next_hashed_owner.capacity() + type_bitmaps.capacity(),
+            Bytes(ref b) => b.capacity(),
+            _ => 0
+        }
+    }
+}
+
 #[derive (Debug, Clone)]
 pub struct Message {
     pub tx_id: u16,
@@ -110,13 +357,73 @@ pub struct Message {
     pub questions: SmallVec<[Question;2]>,
     pub answers: SmallVec<[ResourceRecord;8]>,
     pub name_server: Vec<ResourceRecord>,
-    pub additional: Vec<ResourceRecord>
+    pub additional: Vec<ResourceRecord>,
+    /// the OPT pseudo-RR, kept out of the additional section
+    pub edns: Option<Edns>
+}
+
+/// The EDNS(0) information carried in an OPT record (RFC 6891).
+#[derive (Debug, Clone, PartialEq)]
+pub struct Edns {
+    pub payload_size: u16,
+    /// the upper eight bits of the return code
+    pub extended_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<(u16, Vec<u8>)>
+}
+
+impl Edns {
+    pub fn new(payload_size: u16) -> Edns {
+        Edns {
+            payload_size: payload_size,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![]
+        }
+    }
+
+    /// What we answer a query carrying this with, advertising our own
+    /// payload size.
+    pub fn response(&self) -> Edns {
+        Edns {
+            dnssec_ok: self.dnssec_ok,
+            .. Edns::new(EDNS_PAYLOAD_SIZE)
+        }
+    }
+
+    fn from_record(r: ResourceRecord) -> Result<Edns, Error> {
+        if !r.r_name.is_empty() {
+            return Err(Error::Parse)
+        }
+
+        let mut options = vec![];
+        if let ResourceData::Bytes(ref b) = r.r_data {
+            let mut p = Parser::new(b);
+            while p.peek_u8().is_some() {
+                let code = try!(p.read_u16());
+                let len = try!(p.read_u16());
+                options.push((code, try!(p.read_bytes(len))));
+            }
+        }
+
+        let ttl = r.r_ttl as u32;
+        Ok(Edns {
+            payload_size: r.r_class,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: (ttl & 0x8000) != 0,
+            options: options
+        })
+    }
 }
 
 #[derive (Debug)]
 pub enum Error {
     Byte(byteorder::Error),
-    Parse
+    Parse,
+    Encode
 }
 
 impl From<byteorder::Error> for Error {
@@ -125,6 +432,12 @@ impl From<byteorder::Error> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Byte(byteorder::Error::Io(err))
+    }
+}
+
 impl Message {
     pub fn default() -> Message {
         Message {
@@ -133,7 +446,8 @@ impl Message {
             questions: SmallVec::new(),
             answers: SmallVec::new(),
             name_server: vec![],
-            additional: vec![]
+            additional: vec![],
+            edns: None
         }
     }
 
@@ -152,40 +466,272 @@ impl Message {
         &self.answers
     }
 
+    pub fn authority(&self) -> &[ResourceRecord] {
+        &self.name_server
+    }
+
+    pub fn additional(&self) -> &[ResourceRecord] {
+        &self.additional
+    }
+
     pub fn parse(&mut self, b: &[u8]) -> Result<(), Error> {
         Parser::parse(self, b)
     }
 
-    fn is_query(&self) -> bool {
-        (self.flags & (1 << 15)) == 0
+    /// Encodes the message in wire format, with name compression.
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let mut writer = Writer::new();
+        try!(writer.write_message(self));
+        writer.finish(w)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = Writer::new();
+        try!(writer.write_message(self));
+        Ok(writer.buf)
+    }
+
+    pub fn set_return_code(&mut self, rcode: u16) {
+        self.flags = (self.flags & !0b1111) | (rcode & 0b1111);
+    }
+
+    /// The flags for a response to this query, the opcode and RD bit are
+    /// copied over and recursion is marked as available.
+    pub fn response_flags(&self) -> u16 {
+        (1 << 15) | (self.flags & 0b01111001_00000000) | 0b10000000
+    }
+
+    /// Builds a response to this query carrying `answers`.
+    pub fn response(&self, answers: &[ResourceRecord]) -> Message {
+        Message {
+            tx_id: self.tx_id,
+            flags: self.response_flags(),
+            questions: self.questions.clone(),
+            answers: answers.iter().cloned().collect(),
+            name_server: vec![],
+            additional: vec![],
+            edns: self.edns.as_ref().map(Edns::response)
+        }
+    }
+
+    /// A copy of the message without any records and the TC bit set, for
+    /// when it doesn't fit into the client's payload size.
+    pub fn truncated(&self) -> Message {
+        Message {
+            tx_id: self.tx_id,
+            flags: self.flags | FLAG_TC,
+            questions: self.questions.clone(),
+            edns: self.edns.clone(),
+            .. Message::default()
+        }
+    }
+
+    /// The largest response the sender of this message accepts over UDP.
+    pub fn max_payload(&self) -> usize {
+        let size = self.edns.as_ref().map_or(MIN_PAYLOAD_SIZE, |e| e.payload_size);
+        cmp::max(size, MIN_PAYLOAD_SIZE) as usize
+    }
+
+    /// The full twelve bit return code, including the EDNS extension.
+    pub fn extended_return_code(&self) -> u16 {
+        let upper = self.edns.as_ref().map_or(0, |e| e.extended_rcode as u16);
+        (upper << 4) | self.return_code()
+    }
+
+    fn flag(&self, mask: u16) -> bool {
+        (self.flags & mask) != 0
+    }
+
+    fn set_flag(&mut self, mask: u16, on: bool) {
+        if on {
+            self.flags |= mask;
+        } else {
+            self.flags &= !mask;
+        }
+    }
+
+    pub fn is_query(&self) -> bool {
+        !self.is_response()
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flag(FLAG_QR)
+    }
+
+    pub fn set_response(&mut self, on: bool) {
+        self.set_flag(FLAG_QR, on)
+    }
+
+    pub fn is_auth_answer(&self) -> bool {
+        self.flag(FLAG_AA)
+    }
+
+    pub fn set_auth_answer(&mut self, on: bool) {
+        self.set_flag(FLAG_AA, on)
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.flag(FLAG_TC)
+    }
+
+    pub fn set_truncated(&mut self, on: bool) {
+        self.set_flag(FLAG_TC, on)
+    }
+
+    pub fn recursion_desired(&self) -> bool {
+        self.flag(FLAG_RD)
+    }
+
+    pub fn set_recursion_desired(&mut self, on: bool) {
+        self.set_flag(FLAG_RD, on)
+    }
+
+    pub fn recursion_available(&self) -> bool {
+        self.flag(FLAG_RA)
     }
 
-    fn is_response(&self) -> bool {
-        !self.is_query()
+    pub fn set_recursion_available(&mut self, on: bool) {
+        self.set_flag(FLAG_RA, on)
     }
 
-    fn is_auth_answer(&self) -> bool {
-        (self.flags & 0b0_0000_1_00_00000000) != 0
+    pub fn authentic_data(&self) -> bool {
+        self.flag(FLAG_AD)
     }
 
-    fn is_truncated(&self) -> bool {
-        (self.flags & 0b0_0000_0_1_0_00000000) != 0
+    pub fn set_authentic_data(&mut self, on: bool) {
+        self.set_flag(FLAG_AD, on)
     }
 
-    fn recursion_desired(&self) -> bool {
-        (self.flags & 0b0_0000_0_0_1_00000000) != 0
+    pub fn checking_disabled(&self) -> bool {
+        self.flag(FLAG_CD)
     }
 
-    fn recursion_available(&self) -> bool {
-        (self.flags & 0b0000000010000000) != 0
+    pub fn set_checking_disabled(&mut self, on: bool) {
+        self.set_flag(FLAG_CD, on)
     }
 
-    fn return_code(&self) -> u16 {
+    pub fn return_code(&self) -> u16 {
         return self.flags & 0b1111
     }
 
-    fn opcode(&self) -> u16 {
-       (self.flags & 0b01111000_00000000) >> (3 + 8)
+    /// The return code including the EDNS extension.
+    pub fn rcode(&self) -> Rcode {
+        Rcode::new(self.extended_return_code())
+    }
+
+    /// Sets the return code, the upper bits only make it into the message
+    /// if it has an OPT record to carry them.
+    pub fn set_rcode(&mut self, rcode: Rcode) {
+        let code = rcode.code();
+        self.set_return_code(code);
+        if let Some(ref mut edns) = self.edns {
+            edns.extended_rcode = (code >> 4) as u8;
+        }
+    }
+
+    pub fn opcode(&self) -> Opcode {
+       Opcode::new((self.flags & 0b01111000_00000000) >> (3 + 8))
+    }
+
+    pub fn set_opcode(&mut self, opcode: Opcode) {
+        self.flags = (self.flags & !0b01111000_00000000) | ((opcode.code() & 0b1111) << (3 + 8));
+    }
+}
+
+/// Puts messages together in code, starting from an empty query or from
+/// the response to one.
+#[derive (Debug)]
+pub struct MessageBuilder {
+    message: Message
+}
+
+impl MessageBuilder {
+    /// A standard query asking for recursion.
+    pub fn query(tx_id: u16) -> MessageBuilder {
+        let mut message = Message::default();
+        message.tx_id = tx_id;
+        message.set_recursion_desired(true);
+
+        MessageBuilder { message: message }
+    }
+
+    /// An empty response to `query`, see `Message::response`.
+    pub fn response(query: &Message) -> MessageBuilder {
+        MessageBuilder { message: query.response(&[]) }
+    }
+
+    pub fn question(mut self, name: &str, q_type: QuestionType, q_class: QuestionClass) -> MessageBuilder {
+        self.message.questions.push(Question {
+            q_name: name.bytes().collect(),
+            q_type: q_type,
+            q_class: q_class
+        });
+        self
+    }
+
+    pub fn answer(mut self, r: ResourceRecord) -> MessageBuilder {
+        self.message.answers.push(r);
+        self
+    }
+
+    pub fn authority(mut self, r: ResourceRecord) -> MessageBuilder {
+        self.message.name_server.push(r);
+        self
+    }
+
+    pub fn additional(mut self, r: ResourceRecord) -> MessageBuilder {
+        self.message.additional.push(r);
+        self
+    }
+
+    pub fn edns(mut self, edns: Option<Edns>) -> MessageBuilder {
+        self.message.edns = edns;
+        self
+    }
+
+    pub fn opcode(mut self, opcode: Opcode) -> MessageBuilder {
+        self.message.set_opcode(opcode);
+        self
+    }
+
+    /// Set after the EDNS record, for the extended bits to stick.
+    pub fn rcode(mut self, rcode: Rcode) -> MessageBuilder {
+        self.message.set_rcode(rcode);
+        self
+    }
+
+    pub fn auth_answer(mut self, on: bool) -> MessageBuilder {
+        self.message.set_auth_answer(on);
+        self
+    }
+
+    pub fn truncated(mut self, on: bool) -> MessageBuilder {
+        self.message.set_truncated(on);
+        self
+    }
+
+    pub fn recursion_desired(mut self, on: bool) -> MessageBuilder {
+        self.message.set_recursion_desired(on);
+        self
+    }
+
+    pub fn recursion_available(mut self, on: bool) -> MessageBuilder {
+        self.message.set_recursion_available(on);
+        self
+    }
+
+    pub fn authentic_data(mut self, on: bool) -> MessageBuilder {
+        self.message.set_authentic_data(on);
+        self
+    }
+
+    pub fn checking_disabled(mut self, on: bool) -> MessageBuilder {
+        self.message.set_checking_disabled(on);
+        self
+    }
+
+    pub fn build(self) -> Message {
+        self.message
     }
 }
 
@@ -216,65 +762,87 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_encoded_string(&mut self, s: &mut Name) -> Result<(), Error> {
+    /// Walks a possibly compressed name, handing each label to `label`.
+    /// Pointers have to point before the start of the labels they are part
+    /// of, so following them always makes progress towards the start of the
+    /// message and can't loop.
+    fn walk_name<F>(&mut self, mut label: F) -> Result<(), Error>
+        where F: FnMut(&[u8]) -> Result<(), Error>
+    {
         const OFFSET_MASK: u8 = 0b1100_0000;
+        // where the name ends in the message once compression pointers
+        // have been followed
+        let mut resume = None;
+        // start of the labels being read
+        let mut start = self.cursor.position();
+        // length of the name in wire format, including the root label
+        let mut len = 1;
+
+        loop {
+            let c = try!(self.cursor.read_u8());
 
-        while let Some(c) = self.peek_u8() {
             if c == 0 {
-                try!(self.cursor.read_u8());
                 break
             }
-            else if (c & OFFSET_MASK) == OFFSET_MASK { //10 & 01 are invalid
-                let offset = try!(self.read_u16()) & !((OFFSET_MASK as u16) << 8);
-                let pos = self.cursor.position();
-
-                assert!((offset as usize) < self.bytes.len());
-
-                self.cursor.set_position(offset as u64);
-                loop {
-                    if let Some(cc) = self.peek_u8() {
-                        if cc == 0 {
-                            try!(self.cursor.read_u8());
-                            break
-                        } else {
-                            try!(self.read_label(s));
-                        }
-                    } else {
-                        return Err(Error::Parse)
-                    }
+            else if (c & OFFSET_MASK) == OFFSET_MASK {
+                let offset = (((c & !OFFSET_MASK) as u64) << 8) | try!(self.cursor.read_u8()) as u64;
+
+                if offset >= start {
+                    return Err(Error::Parse)
+                }
+
+                if resume.is_none() {
+                    resume = Some(self.cursor.position());
                 }
-                self.cursor.set_position(pos);
-                return Ok(())
+                start = offset;
+                self.cursor.set_position(offset);
+            }
+            else if (c & OFFSET_MASK) != 0 { // 10 & 01 are reserved
+                return Err(Error::Parse)
             } else {
-                try!(self.read_label(s));
+                len += 1 + c as usize;
+                if len > NAMES_MAX_LENGTH {
+                    return Err(Error::Parse)
+                }
+
+                let pos = self.cursor.position() as usize;
+                if pos + c as usize > self.bytes.len() {
+                    return Err(Error::Parse)
+                }
+                try!(label(&self.bytes[pos..pos + c as usize]));
+                self.cursor.set_position((pos + c as usize) as u64);
             }
         }
+
+        if let Some(pos) = resume {
+            self.cursor.set_position(pos);
+        }
         Ok(())
     }
 
-
-    fn read_label(&mut self, s: &mut Name) -> Result<(), Error> {
-        let c = try!(self.cursor.read_u8());
-        assert!(c != 0);
-
-        if (c as usize) < LABEL_MAX_LENGTH {
+    /// Reads a possibly compressed name into its dotted form.
+    fn parse_encoded_string(&mut self, s: &mut Name) -> Result<(), Error> {
+        self.walk_name(|label| {
             if !s.is_empty() {
                 s.push(b'.');
             }
-            for _ in 0..c {
-                s.push(try!(self.cursor.read_u8()));
+            for &c in label.iter() {
+                // names are kept dotted, a dot inside a label can't be told
+                // apart from the ones between them
+                if c == b'.' {
+                    return Err(Error::Parse)
+                }
+                s.push(c);
             }
-        } else {
-            return Err(Error::Parse)
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
     fn parse_question(&mut self) -> Result<Question,Error> {
         let mut name = Name::new();
         try!(self.parse_encoded_string(&mut name));
-        let q_type = try!(QuestionType::new(try!(self.read_u16())).ok_or(Error::Parse));
-        let q_class = try!(QuestionClass::new(try!(self.read_u16())).ok_or(Error::Parse));
+        let q_type = QuestionType::new(try!(self.read_u16()));
+        let q_class = QuestionClass::new(try!(self.read_u16()));
 
         Ok(Question {
             q_name: name,
@@ -292,6 +860,157 @@ impl<'a> Parser<'a> {
         Ok(Ipv4Addr::new(a, b, c, d))
     }
 
+    fn read_soa(&mut self) -> Result<ResourceData, Error> {
+        Ok(ResourceData::SOA {
+            mname: try!(self.read_name()),
+            rname: try!(self.read_name()),
+            serial: try!(self.cursor.read_u32::<BigEndian>()),
+            refresh: try!(self.cursor.read_u32::<BigEndian>()),
+            retry: try!(self.cursor.read_u32::<BigEndian>()),
+            expire: try!(self.cursor.read_u32::<BigEndian>()),
+            minimum: try!(self.cursor.read_u32::<BigEndian>())
+        })
+    }
+
+    fn read_ipv6(&mut self) -> Result<Ipv6Addr, Error> {
+        let mut s = [0u16; 8];
+        for i in s.iter_mut() {
+            *i = try!(self.read_u16());
+        }
+
+        Ok(Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]))
+    }
+
+    fn read_name(&mut self) -> Result<Name, Error> {
+        let mut name = Name::new();
+        try!(self.parse_encoded_string(&mut name));
+        Ok(name)
+    }
+
+    fn read_character_string(&mut self) -> Result<Vec<u8>, Error> {
+        let len = try!(self.cursor.read_u8());
+        self.read_bytes(len as u16)
+    }
+
+    /// The bytes left before `end`, for fields that run to the end of the
+    /// RDATA.
+    fn remaining(&self, end: u64) -> Result<u16, Error> {
+        if self.cursor.position() > end {
+            return Err(Error::Parse)
+        }
+        Ok((end - self.cursor.position()) as u16)
+    }
+
+    fn read_rdata(&mut self, t: u16, rd_len: u16) -> Result<ResourceData, Error> {
+        let end = self.cursor.position() + rd_len as u64;
+
+        Ok(match t {
+            0x01 => ResourceData::A(try!(self.read_ipv4())),
+            0x02 => ResourceData::NS(try!(self.read_name())),
+            0x05 => ResourceData::CNAME(try!(self.read_name())),
+            0x06 => try!(self.read_soa()),
+            0x0c => ResourceData::PTR(try!(self.read_name())),
+            0x0d => ResourceData::HINFO {
+                cpu: try!(self.read_character_string()),
+                os: try!(self.read_character_string())
+            },
+            0x0f => ResourceData::MX {
+                preference: try!(self.read_u16()),
+                exchange: try!(self.read_name())
+            },
+            0x10 => {
+                let mut strings = Vec::new();
+                while self.cursor.position() < end {
+                    strings.push(try!(self.read_character_string()));
+                }
+                ResourceData::TXT(strings)
+            },
+            0x1c => ResourceData::AAAA(try!(self.read_ipv6())),
+            0x21 => ResourceData::SRV {
+                priority: try!(self.read_u16()),
+                weight: try!(self.read_u16()),
+                port: try!(self.read_u16()),
+                target: try!(self.read_name())
+            },
+            0x23 => ResourceData::NAPTR {
+                order: try!(self.read_u16()),
+                preference: try!(self.read_u16()),
+                flags: try!(self.read_character_string()),
+                services: try!(self.read_character_string()),
+                regexp: try!(self.read_character_string()),
+                replacement: try!(self.read_name())
+            },
+            0x2b => ResourceData::DS {
+                key_tag: try!(self.read_u16()),
+                algorithm: try!(self.cursor.read_u8()),
+                digest_type: try!(self.cursor.read_u8()),
+                digest: {
+                    let len = try!(self.remaining(end));
+                    try!(self.read_bytes(len))
+                }
+            },
+            0x2e => ResourceData::RRSIG {
+                type_covered: try!(self.read_u16()),
+                algorithm: try!(self.cursor.read_u8()),
+                labels: try!(self.cursor.read_u8()),
+                original_ttl: try!(self.cursor.read_u32::<BigEndian>()),
+                expiration: try!(self.cursor.read_u32::<BigEndian>()),
+                inception: try!(self.cursor.read_u32::<BigEndian>()),
+                key_tag: try!(self.read_u16()),
+                signer_name: try!(self.read_name()),
+                signature: {
+                    let len = try!(self.remaining(end));
+                    try!(self.read_bytes(len))
+                }
+            },
+            0x2f => ResourceData::NSEC {
+                next_domain: try!(self.read_name()),
+                type_bitmaps: {
+                    let len = try!(self.remaining(end));
+                    try!(self.read_bytes(len))
+                }
+            },
+            0x30 => ResourceData::DNSKEY {
+                flags: try!(self.read_u16()),
+                protocol: try!(self.cursor.read_u8()),
+                algorithm: try!(self.cursor.read_u8()),
+                public_key: {
+                    let len = try!(self.remaining(end));
+                    try!(self.read_bytes(len))
+                }
+            },
+            0x32 => {
+                let hash_algorithm = try!(self.cursor.read_u8());
+                let flags = try!(self.cursor.read_u8());
+                let iterations = try!(self.read_u16());
+                let salt = try!(self.read_character_string());
+                let next_hashed_owner = try!(self.read_character_string());
+                let len = try!(self.remaining(end));
+
+                ResourceData::NSEC3 {
+                    hash_algorithm: hash_algorithm,
+                    flags: flags,
+                    iterations: iterations,
+                    salt: salt,
+                    next_hashed_owner: next_hashed_owner,
+                    type_bitmaps: try!(self.read_bytes(len))
+                }
+            },
+            0x101 => {
+                let flags = try!(self.cursor.read_u8());
+                let tag = try!(self.read_character_string());
+                let len = try!(self.remaining(end));
+
+                ResourceData::CAA {
+                    flags: flags,
+                    tag: tag,
+                    value: try!(self.read_bytes(len))
+                }
+            },
+            _ => ResourceData::Bytes(try!(self.read_bytes(rd_len)))
+        })
+    }
+
     fn read_bytes(&mut self, len: u16) -> Result<Vec<u8>, Error> {
         let mut v = Vec::with_capacity(len as usize);
 
@@ -309,11 +1028,13 @@ impl<'a> Parser<'a> {
         let class = try!(self.read_u16());
         let ttl = try!(self.cursor.read_i32::<BigEndian>());
         let rd_len = try!(self.read_u16());
+        let rd_end = self.cursor.position() + rd_len as u64;
 
-        let rdata = match t {
-            0x01 => ResourceData::A(try!(self.read_ipv4())),
-            _ => ResourceData::Bytes(try!(self.read_bytes(rd_len)))
-        };
+        let rdata = try!(self.read_rdata(t, rd_len));
+
+        if self.cursor.position() != rd_end {
+            return Err(Error::Parse)
+        }
 
         Ok(ResourceRecord{
             r_name: name,
@@ -324,6 +1045,48 @@ impl<'a> Parser<'a> {
         })
     }
 
+    /// Skips a name, whatever its labels hold.
+    fn name_ref(&mut self) -> Result<NameRef<'a>, Error> {
+        let pos = self.cursor.position() as usize;
+        try!(self.walk_name(|_| Ok(())));
+
+        Ok(NameRef { bytes: self.bytes, pos: pos })
+    }
+
+    fn skip_question(&mut self) -> Result<QuestionRef<'a>, Error> {
+        Ok(QuestionRef {
+            name: try!(self.name_ref()),
+            q_type: QuestionType::new(try!(self.read_u16())),
+            q_class: QuestionClass::new(try!(self.read_u16()))
+        })
+    }
+
+    fn skip_resource_record(&mut self, section: Section) -> Result<RecordRef<'a>, Error> {
+        let start = self.cursor.position() as usize;
+        let name = try!(self.name_ref());
+        let t = try!(self.read_u16());
+        let class = try!(self.read_u16());
+        let ttl = try!(self.cursor.read_i32::<BigEndian>());
+        let rd_len = try!(self.read_u16()) as usize;
+        let rd_start = self.cursor.position() as usize;
+
+        if rd_start + rd_len > self.bytes.len() {
+            return Err(Error::Parse)
+        }
+        self.cursor.set_position((rd_start + rd_len) as u64);
+
+        Ok(RecordRef {
+            section: section,
+            name: name,
+            r_type: t,
+            r_class: class,
+            r_ttl: ttl,
+            rdata: &self.bytes[rd_start..rd_start + rd_len],
+            start: start,
+            end: rd_start + rd_len
+        })
+    }
+
     pub fn parse(m: &mut Message, b: &[u8]) -> Result<(),Error> {
         let mut p = Parser::new(b);
         let txn_id = try!(p.read_u16());
@@ -341,13 +1104,606 @@ impl<'a> Parser<'a> {
             m.answers.push(try!(p.parse_resource_record()));
         }
 
-        m.tx_id = txn_id;
+        for _ in 0..ns_count {
+            m.name_server.push(try!(p.parse_resource_record()));
+        }
+
+        for _ in 0..ar_count {
+            let r = try!(p.parse_resource_record());
+
+            if r.r_type == TYPE_OPT {
+                // only one is allowed per message
+                if m.edns.is_some() {
+                    return Err(Error::Parse)
+                }
+                m.edns = Some(try!(Edns::from_record(r)));
+            } else {
+                m.additional.push(r);
+            }
+        }
+
+        m.tx_id = txn_id;
         m.flags = flags;
 
         Ok(())
     }
 }
 
+/// A message in wire format that is only decoded as far as it's looked
+/// at. Nothing is copied out of the packet until asked for, names are
+/// checked on the stack.
+#[derive (Debug, Clone, Copy)]
+pub struct MessageRef<'a> {
+    bytes: &'a [u8]
+}
+
+#[derive (Debug, PartialEq, Copy, Clone)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional
+}
+
+/// A possibly compressed name inside a packet.
+#[derive (Debug, Clone, Copy)]
+pub struct NameRef<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+#[derive (Debug, Clone, Copy)]
+pub struct QuestionRef<'a> {
+    pub name: NameRef<'a>,
+    pub q_type: QuestionType,
+    pub q_class: QuestionClass
+}
+
+#[derive (Debug, Clone, Copy)]
+pub struct RecordRef<'a> {
+    pub section: Section,
+    pub name: NameRef<'a>,
+    pub r_type: u16,
+    pub r_class: u16,
+    pub r_ttl: i32,
+    pub rdata: &'a [u8],
+    /// where the record starts and ends in the packet
+    pub start: usize,
+    pub end: usize
+}
+
+pub struct Questions<'a> {
+    parser: Parser<'a>,
+    remaining: u16
+}
+
+pub struct Records<'a> {
+    parser: Parser<'a>,
+    counts: [u16; 3],
+    section: usize
+}
+
+impl<'a> MessageRef<'a> {
+    pub fn new(b: &'a [u8]) -> Result<MessageRef<'a>, Error> {
+        if b.len() < 12 {
+            return Err(Error::Parse)
+        }
+        Ok(MessageRef { bytes: b })
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    fn u16_at(&self, pos: usize) -> u16 {
+        ((self.bytes[pos] as u16) << 8) | self.bytes[pos + 1] as u16
+    }
+
+    pub fn tx_id(&self) -> u16 {
+        self.u16_at(0)
+    }
+
+    pub fn flags(&self) -> u16 {
+        self.u16_at(2)
+    }
+
+    pub fn is_response(&self) -> bool {
+        (self.flags() & FLAG_QR) != 0
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        (self.flags() & FLAG_TC) != 0
+    }
+
+    pub fn return_code(&self) -> u16 {
+        self.flags() & 0b1111
+    }
+
+    /// The return code including the EDNS extension.
+    pub fn rcode(&self) -> Rcode {
+        let upper = self.edns().map_or(0, |r| (r.r_ttl as u32) >> 24);
+        Rcode::new(((upper as u16) << 4) | self.return_code())
+    }
+
+    pub fn question_count(&self) -> u16 {
+        self.u16_at(4)
+    }
+
+    pub fn questions(&self) -> Questions<'a> {
+        let mut parser = Parser::new(self.bytes);
+        parser.cursor.set_position(12);
+
+        Questions {
+            parser: parser,
+            remaining: self.question_count()
+        }
+    }
+
+    /// The records of all three sections in the order they appear.
+    pub fn records(&self) -> Records<'a> {
+        let mut questions = self.questions();
+        while let Some(Ok(_)) = questions.next() {}
+
+        Records {
+            parser: questions.parser,
+            counts: [self.u16_at(6), self.u16_at(8), self.u16_at(10)],
+            section: 0
+        }
+    }
+
+    /// The OPT record, if there is one.
+    pub fn edns(&self) -> Option<RecordRef<'a>> {
+        self.records()
+            .filter_map(|r| r.ok())
+            .find(|r| r.section == Section::Additional && r.r_type == TYPE_OPT)
+    }
+
+    /// Whether the first question is the one asked in `m`.
+    pub fn answers_question(&self, m: &Message) -> bool {
+        match (self.questions().next(), m.questions().first()) {
+            (Some(Ok(ref q)), Some(question)) => {
+                q.q_type == question.q_type && q.q_class == question.q_class &&
+                    q.name.eq_name(&question.q_name)
+            },
+            (None, None) => true,
+            _ => false
+        }
+    }
+
+    /// Whether the first question is the one asked in `request`, for
+    /// requests that couldn't be decoded into a `Message`.
+    pub fn answers_request(&self, request: &MessageRef) -> bool {
+        match (self.questions().next(), request.questions().next()) {
+            (Some(Ok(ref a)), Some(Ok(ref q))) => {
+                a.q_type == q.q_type && a.q_class == q.q_class && a.name.eq_name_ref(&q.name)
+            },
+            (None, None) => true,
+            _ => false
+        }
+    }
+
+    /// The largest response the sender of this message accepts over UDP.
+    pub fn max_payload(&self) -> usize {
+        let size = self.edns().map_or(MIN_PAYLOAD_SIZE, |r| r.r_class);
+        cmp::max(size, MIN_PAYLOAD_SIZE) as usize
+    }
+
+    /// Copies the message with its OPT record swapped for `edns`, or
+    /// dropped if that is `None`. The extended return code of the OPT record
+    /// replaced is kept, so the records are only walked once. This only
+    /// works if the OPT record is the last one in the message, anything
+    /// after it could be compressed against names that would move.
+    pub fn write_with_edns<W: Write>(&self, edns: Option<&Edns>, w: &mut W) -> Result<(), Error> {
+        let mut end = self.bytes.len();
+        let mut ar_count = self.u16_at(10);
+        let mut extended_rcode = 0;
+
+        for r in self.records() {
+            let r = try!(r);
+            if r.r_type == TYPE_OPT && r.section == Section::Additional {
+                if r.end != self.bytes.len() {
+                    return Err(Error::Encode)
+                }
+                end = r.start;
+                ar_count -= 1;
+                extended_rcode = ((r.r_ttl as u32) >> 24) as u8;
+            }
+        }
+
+        let edns = edns.map(|e| Edns { extended_rcode: extended_rcode, .. e.clone() });
+
+        try!(w.write_all(&self.bytes[..10]));
+        try!(w.write_u16::<BigEndian>(ar_count + edns.is_some() as u16));
+        try!(w.write_all(&self.bytes[12..end]));
+        write_edns_record(edns.as_ref(), w)
+    }
+
+    /// Writes the header and question of the message with the TC bit set
+    /// and no records apart from `edns`.
+    pub fn write_truncated<W: Write>(&self, edns: Option<&Edns>, w: &mut W) -> Result<(), Error> {
+        let mut questions = self.questions();
+        while let Some(q) = questions.next() {
+            try!(q);
+        }
+        let end = questions.parser.cursor.position() as usize;
+
+        for i in [self.tx_id(), self.flags() | FLAG_TC, self.question_count(), 0, 0, edns.is_some() as u16].iter() {
+            try!(w.write_u16::<BigEndian>(*i));
+        }
+        try!(w.write_all(&self.bytes[12..end]));
+        write_edns_record(edns, w)
+    }
+
+    /// Writes an answer to this request carrying nothing but `rcode`, for
+    /// requests that can't be taken on. The question is only repeated if it
+    /// can be read.
+    pub fn write_error<W: Write>(&self, rcode: Rcode, w: &mut W) -> Result<(), Error> {
+        let mut questions = self.questions();
+        let end = match (self.question_count(), questions.next()) {
+            (1, Some(Ok(_))) => questions.parser.cursor.position() as usize,
+            _ => 12
+        };
+        let flags = FLAG_QR | (self.flags() & 0b01111001_00000000) | 0b10000000 | (rcode.code() & 0b1111);
+
+        for i in [self.tx_id(), flags, (end > 12) as u16, 0, 0, 0].iter() {
+            try!(w.write_u16::<BigEndian>(*i));
+        }
+        try!(w.write_all(&self.bytes[12..end]));
+        Ok(())
+    }
+
+    /// Decodes the whole message.
+    pub fn to_message(&self) -> Result<Message, Error> {
+        Message::new(self.bytes)
+    }
+}
+
+fn write_edns_record<W: Write>(edns: Option<&Edns>, w: &mut W) -> Result<(), Error> {
+    match edns {
+        Some(edns) => {
+            let mut writer = Writer::uncompressed();
+            try!(writer.write_edns(edns));
+            writer.finish(w)
+        },
+        None => Ok(())
+    }
+}
+
+impl<'a> NameRef<'a> {
+    pub fn to_name(&self) -> Result<Name, Error> {
+        let mut p = Parser::new(self.bytes);
+        p.cursor.set_position(self.pos as u64);
+        p.read_name()
+    }
+
+    /// Compares to `name` ignoring case.
+    pub fn eq_name(&self, name: &Name) -> bool {
+        match self.to_name() {
+            Ok(n) => n.len() == name.len() &&
+                n.iter().zip(name.iter()).all(|(a, b)| a.to_ascii_lowercase() == b.to_ascii_lowercase()),
+            Err(_) => false
+        }
+    }
+
+    /// Compares to another name ignoring case, labels are compared as they
+    /// are whatever they hold.
+    pub fn eq_name_ref(&self, other: &NameRef) -> bool {
+        match (self.labels(), other.labels()) {
+            (Ok(a), Ok(b)) => a.len() == b.len() &&
+                a.iter().zip(b.iter()).all(|(a, b)| a.to_ascii_lowercase() == b.to_ascii_lowercase()),
+            _ => false
+        }
+    }
+
+    /// The name with its pointers followed, as length prefixed labels.
+    fn labels(&self) -> Result<Vec<u8>, Error> {
+        let mut labels = Vec::new();
+        let mut p = Parser::new(self.bytes);
+        p.cursor.set_position(self.pos as u64);
+        try!(p.walk_name(|label| {
+            labels.push(label.len() as u8);
+            labels.extend_from_slice(label);
+            Ok(())
+        }));
+        Ok(labels)
+    }
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = Result<QuestionRef<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<QuestionRef<'a>, Error>> {
+        if self.remaining == 0 {
+            return None
+        }
+        self.remaining -= 1;
+
+        let q = self.parser.skip_question();
+        if q.is_err() {
+            self.remaining = 0;
+        }
+        Some(q)
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<RecordRef<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<RecordRef<'a>, Error>> {
+        while self.section < 3 && self.counts[self.section] == 0 {
+            self.section += 1;
+        }
+        if self.section == 3 {
+            return None
+        }
+        self.counts[self.section] -= 1;
+
+        let section = [Section::Answer, Section::Authority, Section::Additional][self.section];
+        let r = self.parser.skip_resource_record(section);
+        if r.is_err() {
+            self.section = 3;
+        }
+        Some(r)
+    }
+}
+
+/// Encodes messages in wire format. Names are compressed (RFC 1035
+/// section 4.1.4) against the ones already written, inside RDATA only for
+/// the types RFC 3597 allows it for.
+struct Writer {
+    buf: Vec<u8>,
+    /// offsets of the names written so far, keyed by lower cased name
+    names: HashMap<Vec<u8>, u16>,
+    compress: bool
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer {
+            buf: Vec::with_capacity(512),
+            names: HashMap::new(),
+            compress: true
+        }
+    }
+
+    fn uncompressed() -> Writer {
+        Writer {
+            compress: false,
+            .. Writer::new()
+        }
+    }
+
+    fn finish<W: Write>(self, w: &mut W) -> Result<(), Error> {
+        Ok(try!(w.write_all(&self.buf)))
+    }
+
+    fn write_u16(&mut self, i: u16) -> Result<(), Error> {
+        Ok(try!(self.buf.write_u16::<BigEndian>(i)))
+    }
+
+    fn write_u32(&mut self, i: u32) -> Result<(), Error> {
+        Ok(try!(self.buf.write_u32::<BigEndian>(i)))
+    }
+
+    fn write_bytes(&mut self, b: &[u8]) -> Result<(), Error> {
+        self.buf.extend(b.iter().cloned());
+        Ok(())
+    }
+
+    /// Writes a name, pointing at an earlier copy of it or of one of its
+    /// suffixes when `compress` is set.
+    fn write_name(&mut self, name: &Name, compress: bool) -> Result<(), Error> {
+        if name.len() > NAMES_MAX_LENGTH - 2 {
+            return Err(Error::Encode)
+        }
+
+        let mut start = 0;
+
+        while start < name.len() {
+            let suffix: Vec<u8> = name[start..].iter().map(|c| c.to_ascii_lowercase()).collect();
+
+            if compress && self.compress {
+                if let Some(offset) = self.names.get(&suffix) {
+                    return self.write_u16(0xc000 | *offset)
+                }
+            }
+
+            if self.compress && self.buf.len() < 0x4000 {
+                self.names.insert(suffix, self.buf.len() as u16);
+            }
+
+            let end = name[start..].iter().position(|c| *c == b'.').map_or(name.len(), |i| start + i);
+            let label = &name[start..end];
+
+            if label.is_empty() || label.len() > LABEL_MAX_LENGTH {
+                return Err(Error::Encode)
+            }
+            self.buf.push(label.len() as u8);
+            try!(self.write_bytes(label));
+
+            start = end + 1;
+        }
+
+        self.buf.push(0);
+        Ok(())
+    }
+
+    fn write_question(&mut self, q: &Question) -> Result<(), Error> {
+        try!(self.write_name(&q.q_name, true));
+        try!(self.write_u16(q.q_type.code()));
+        self.write_u16(q.q_class.code())
+    }
+
+    fn write_character_string(&mut self, s: &[u8]) -> Result<(), Error> {
+        if s.len() > 0xff {
+            return Err(Error::Encode)
+        }
+        self.buf.push(s.len() as u8);
+        self.write_bytes(s)
+    }
+
+    /// Only the names of the RFC 1035 types are compressed, the later
+    /// ones must be written in full.
+    fn write_rdata(&mut self, rdata: &ResourceData) -> Result<(), Error> {
+        use self::ResourceData::*;
+        match *rdata {
+            A(ref addr) => self.write_bytes(&addr.octets()),
+            AAAA(ref addr) => {
+                for i in addr.segments().iter() {
+                    try!(self.write_u16(*i));
+                }
+                Ok(())
+            },
+            NS(ref name) | CNAME(ref name) | PTR(ref name) => self.write_name(name, true),
+            MX { preference, ref exchange } => {
+                try!(self.write_u16(preference));
+                self.write_name(exchange, true)
+            },
+            SOA { ref mname, ref rname, serial, refresh, retry, expire, minimum } => {
+                try!(self.write_name(mname, true));
+                try!(self.write_name(rname, true));
+                for i in [serial, refresh, retry, expire, minimum].iter() {
+                    try!(self.write_u32(*i));
+                }
+                Ok(())
+            },
+            TXT(ref strings) => {
+                for s in strings.iter() {
+                    try!(self.write_character_string(s));
+                }
+                Ok(())
+            },
+            HINFO { ref cpu, ref os } => {
+                try!(self.write_character_string(cpu));
+                self.write_character_string(os)
+            },
+            SRV { priority, weight, port, ref target } => {
+                try!(self.write_u16(priority));
+                try!(self.write_u16(weight));
+                try!(self.write_u16(port));
+                self.write_name(target, false)
+            },
+            NAPTR { order, preference, ref flags, ref services, ref regexp, ref replacement } => {
+                try!(self.write_u16(order));
+                try!(self.write_u16(preference));
+                try!(self.write_character_string(flags));
+                try!(self.write_character_string(services));
+                try!(self.write_character_string(regexp));
+                self.write_name(replacement, false)
+            },
+            CAA { flags, ref tag, ref value } => {
+                self.buf.push(flags);
+                try!(self.write_character_string(tag));
+                self.write_bytes(value)
+            },
+            DS { key_tag, algorithm, digest_type, ref digest } => {
+                try!(self.write_u16(key_tag));
+                self.buf.push(algorithm);
+                self.buf.push(digest_type);
+                self.write_bytes(digest)
+            },
+            DNSKEY { flags, protocol, algorithm, ref public_key } => {
+                try!(self.write_u16(flags));
+                self.buf.push(protocol);
+                self.buf.push(algorithm);
+                self.write_bytes(public_key)
+            },
+            RRSIG { type_covered, algorithm, labels, original_ttl, expiration, inception,
+                    key_tag, ref signer_name, ref signature } => {
+                try!(self.write_u16(type_covered));
+                self.buf.push(algorithm);
+                self.buf.push(labels);
+                try!(self.write_u32(original_ttl));
+                try!(self.write_u32(expiration));
+                try!(self.write_u32(inception));
+                try!(self.write_u16(key_tag));
+                try!(self.write_name(signer_name, false));
+                self.write_bytes(signature)
+            },
+            NSEC { ref next_domain, ref type_bitmaps } => {
+                try!(self.write_name(next_domain, false));
+                self.write_bytes(type_bitmaps)
+            },
+            NSEC3 { hash_algorithm, flags, iterations, ref salt, ref next_hashed_owner, ref type_bitmaps } => {
+                self.buf.push(hash_algorithm);
+                self.buf.push(flags);
+                try!(self.write_u16(iterations));
+                try!(self.write_character_string(salt));
+                try!(self.write_character_string(next_hashed_owner));
+                self.write_bytes(type_bitmaps)
+            },
+            Bytes(ref b) => self.write_bytes(b)
+        }
+    }
+
+    fn write_resource_record(&mut self, r: &ResourceRecord) -> Result<(), Error> {
+        try!(self.write_name(&r.r_name, true));
+        try!(self.write_u16(r.r_type));
+        try!(self.write_u16(r.r_class));
+        try!(self.buf.write_i32::<BigEndian>(r.r_ttl));
+
+        // the length is filled in once the RDATA has been written
+        let len_pos = self.buf.len();
+        try!(self.write_u16(0));
+        try!(self.write_rdata(&r.r_data));
+
+        let rd_len = self.buf.len() - len_pos - 2;
+        if rd_len > 0xffff {
+            return Err(Error::Encode)
+        }
+        self.buf[len_pos] = (rd_len >> 8) as u8;
+        self.buf[len_pos + 1] = rd_len as u8;
+        Ok(())
+    }
+
+    fn write_message(&mut self, m: &Message) -> Result<(), Error> {
+        try!(self.write_u16(m.tx_id));
+        try!(self.write_u16(m.flags));
+        try!(self.write_u16(m.questions.len() as u16));
+        try!(self.write_u16(m.answers.len() as u16));
+        try!(self.write_u16(m.name_server.len() as u16));
+        try!(self.write_u16(m.additional.len() as u16 + m.edns.is_some() as u16));
+
+        for q in m.questions.iter() {
+            try!(self.write_question(q));
+        }
+
+        for r in m.answers.iter().chain(m.name_server.iter()).chain(m.additional.iter()) {
+            try!(self.write_resource_record(r));
+        }
+
+        if let Some(ref edns) = m.edns {
+            try!(self.write_edns(edns));
+        }
+        Ok(())
+    }
+
+    fn write_edns(&mut self, edns: &Edns) -> Result<(), Error> {
+        // the owner is always the root
+        self.buf.push(0);
+        try!(self.write_u16(TYPE_OPT));
+        try!(self.write_u16(edns.payload_size));
+        try!(self.write_u32(((edns.extended_rcode as u32) << 24) |
+                            ((edns.version as u32) << 16) |
+                            if edns.dnssec_ok { 0x8000 } else { 0 }));
+
+        let len = edns.options.iter().map(|&(_, ref data)| 4 + data.len()).fold(0, |a, b| a + b);
+        if len > 0xffff {
+            return Err(Error::Encode)
+        }
+        try!(self.write_u16(len as u16));
+
+        for &(code, ref data) in edns.options.iter() {
+            if data.len() > 0xffff {
+                return Err(Error::Encode)
+            }
+            try!(self.write_u16(code));
+            try!(self.write_u16(data.len() as u16));
+            try!(self.write_bytes(data));
+        }
+        Ok(())
+    }
+}
+
 pub fn parse_txn_id(bytes: &[u8]) -> Option<u16> {
     if bytes.len() < 2 {
         return None
@@ -368,7 +1724,7 @@ mod tests {
 
         let msg = Message::new(bytes).unwrap();
         assert_eq!(true, msg.is_query());
-        assert_eq!(0, msg.opcode());
+        assert_eq!(Opcode::Query, msg.opcode());
         assert_eq!(true, msg.recursion_desired());
         assert_eq!(false, msg.recursion_available());
         assert_eq!(0, msg.return_code());
@@ -399,7 +1755,7 @@ mod tests {
         let msg = Message::new(bytes).unwrap();
 
         assert!(msg.is_query());
-        assert_eq!(0, msg.opcode());
+        assert_eq!(Opcode::Query, msg.opcode());
         assert_eq!(0, msg.return_code());
         assert_eq!("shops.shopify.com", msg.questions[0].name());
     }
@@ -411,7 +1767,7 @@ mod tests {
         let msg = Message::new(bytes).unwrap();
 
         assert!(msg.is_response());
-        assert_eq!(0, msg.opcode());
+        assert_eq!(Opcode::Query, msg.opcode());
         assert_eq!(0, msg.return_code());
 
         assert_eq!(4, msg.answers.len());
@@ -426,4 +1782,611 @@ mod tests {
         assert_eq!(ResourceData::A(Ipv4Addr::new(23,227,38,69)), msg.answers[2].r_data);
         assert_eq!(ResourceData::A(Ipv4Addr::new(23,227,38,68)), msg.answers[3].r_data);
     }
+
+    #[test]
+    fn authority_soa() {
+        let request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        let mut response = request.response(&[]);
+        response.set_return_code(3);
+        response.name_server.push(ResourceRecord {
+            r_name: "com".bytes().collect(),
+            r_type: 6,
+            r_class: 1,
+            r_ttl: 900,
+            r_data: ResourceData::SOA {
+                mname: "a.gtld-servers.net".bytes().collect(),
+                rname: "nstld.verisign-grs.com".bytes().collect(),
+                serial: 1449658420,
+                refresh: 1800,
+                retry: 900,
+                expire: 604800,
+                minimum: 86400
+            }
+        });
+
+        let mut bytes = Vec::new();
+        response.write(&mut bytes).unwrap();
+
+        let msg = Message::new(&bytes).unwrap();
+
+        assert_eq!(3, msg.return_code());
+        assert_eq!(0, msg.answers.len());
+        assert_eq!(1, msg.name_server.len());
+        assert_eq!("com", msg.name_server[0].name());
+        assert_eq!(response.name_server[0].r_data, msg.name_server[0].r_data);
+    }
+
+    #[test]
+    fn authority_and_additional() {
+        let bytes = include_bytes!("../test/referral_response.bin");
+
+        let msg = Message::new(bytes).unwrap();
+
+        assert_eq!(1, msg.answers().len());
+        assert_eq!(2, msg.authority().len());
+        assert_eq!(2, msg.additional().len());
+
+        for ns in msg.authority().iter() {
+            assert_eq!("example.com", ns.name());
+            assert_eq!(2, ns.r_type);
+        }
+        // the second name server's name is a pointer into the first one's
+        assert_eq!(ResourceData::NS("a.iana-servers.net".bytes().collect()), msg.authority()[0].r_data);
+        assert_eq!(ResourceData::NS("b.iana-servers.net".bytes().collect()), msg.authority()[1].r_data);
+
+        assert_eq!("a.iana-servers.net", msg.additional()[0].name());
+        assert_eq!("b.iana-servers.net", msg.additional()[1].name());
+        assert_eq!(ResourceData::A(Ipv4Addr::new(199,43,135,53)), msg.additional()[0].r_data);
+        assert_eq!(ResourceData::A(Ipv4Addr::new(199,43,133,53)), msg.additional()[1].r_data);
+        assert_eq!(172800, msg.additional()[1].r_ttl);
+    }
+
+    #[test]
+    fn response_from_answers() {
+        let request = Message::new(include_bytes!("../test/multi_a_request.bin")).unwrap();
+        let upstream = Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap();
+
+        let mut bytes = Vec::new();
+        request.response(upstream.answers()).write(&mut bytes).unwrap();
+
+        let msg = Message::new(&bytes).unwrap();
+
+        assert!(msg.is_response());
+        assert!(msg.recursion_desired());
+        assert!(msg.recursion_available());
+        assert_eq!(request.tx_id, msg.tx_id);
+        assert_eq!("shops.shopify.com", msg.questions[0].name());
+        assert_eq!(4, msg.answers.len());
+        for (a, b) in msg.answers.iter().zip(upstream.answers.iter()) {
+            assert_eq!(a.name(), b.name());
+            assert_eq!(a.r_ttl, b.r_ttl);
+            assert_eq!(a.r_data, b.r_data);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for bytes in [&include_bytes!("../test/dns_request.bin")[..],
+                      &include_bytes!("../test/dns_response.bin")[..],
+                      &include_bytes!("../test/multi_a_request.bin")[..],
+                      &include_bytes!("../test/multi_a_response.bin")[..],
+                      &include_bytes!("../test/referral_response.bin")[..]].iter() {
+            let msg = Message::new(bytes).unwrap();
+            assert_eq!(&bytes[..], &msg.to_bytes().unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn name_compression() {
+        let request = Message::new(include_bytes!("../test/multi_a_request.bin")).unwrap();
+        let upstream = Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap();
+
+        let mut response = request.response(upstream.answers());
+        response.answers[0].r_name = "Shops.Shopify.com".bytes().collect();
+        response.name_server.push(ResourceRecord {
+            r_name: "shopify.com".bytes().collect(),
+            r_type: 6,
+            r_class: 1,
+            r_ttl: 300,
+            r_data: ResourceData::SOA {
+                mname: "ns1.shopify.com".bytes().collect(),
+                rname: "hostmaster.shopify.com".bytes().collect(),
+                serial: 1,
+                refresh: 2,
+                retry: 3,
+                expire: 4,
+                minimum: 5
+            }
+        });
+
+        let bytes = response.to_bytes().unwrap();
+        // the owner names and the SOA's suffixes all point back into the question
+        assert_eq!(99 + 2 + 10 + 6 + 13 + 20, bytes.len());
+
+        let msg = Message::new(&bytes).unwrap();
+        assert_eq!("shops.shopify.com", msg.answers[3].name());
+        assert_eq!("shopify.com", msg.name_server[0].name());
+        assert_eq!(response.name_server[0].r_data, msg.name_server[0].r_data);
+
+        let mut uncompressed = Vec::new();
+        msg.name_server[0].write(&mut uncompressed).unwrap();
+        assert_eq!(13 + 10 + 17 + 24 + 20, uncompressed.len());
+    }
+
+    #[test]
+    fn typed_rdata() {
+        let name = |s: &str| -> Name { s.bytes().collect() };
+        let data = vec![
+            (0x1c, ResourceData::AAAA("2001:db8::1".parse().unwrap())),
+            (0x05, ResourceData::CNAME(name("www.example.com"))),
+            (0x0c, ResourceData::PTR(name("host.example.com"))),
+            (0x0f, ResourceData::MX { preference: 10, exchange: name("mail.example.com") }),
+            (0x10, ResourceData::TXT(vec![b"v=spf1 -all".to_vec(), Vec::new()])),
+            (0x0d, ResourceData::HINFO { cpu: b"x86".to_vec(), os: b"linux".to_vec() }),
+            (0x21, ResourceData::SRV { priority: 1, weight: 2, port: 53, target: name("ns.example.com") }),
+            (0x23, ResourceData::NAPTR {
+                order: 100,
+                preference: 10,
+                flags: b"u".to_vec(),
+                services: b"E2U+sip".to_vec(),
+                regexp: b"!^.*$!sip:info@example.com!".to_vec(),
+                replacement: Name::new()
+            }),
+            (0x101, ResourceData::CAA { flags: 0, tag: b"issue".to_vec(), value: b"ca.example.net".to_vec() }),
+            (0x2b, ResourceData::DS { key_tag: 2371, algorithm: 13, digest_type: 2, digest: vec![0xab; 32] }),
+            (0x30, ResourceData::DNSKEY { flags: 257, protocol: 3, algorithm: 13, public_key: vec![1, 2, 3, 4] }),
+            (0x2e, ResourceData::RRSIG {
+                type_covered: 1,
+                algorithm: 13,
+                labels: 2,
+                original_ttl: 3600,
+                expiration: 1500000000,
+                inception: 1400000000,
+                key_tag: 2371,
+                signer_name: name("example.com"),
+                signature: vec![9; 64]
+            }),
+            (0x2f, ResourceData::NSEC { next_domain: name("a.example.com"), type_bitmaps: vec![0, 1, 0x40] }),
+            (0x32, ResourceData::NSEC3 {
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 10,
+                salt: vec![0xaa, 0xbb],
+                next_hashed_owner: vec![7; 20],
+                type_bitmaps: vec![0, 1, 0x40]
+            }),
+            (0x63, ResourceData::Bytes(vec![1, 2, 3]))
+        ];
+
+        let request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        let records: Vec<ResourceRecord> = data.into_iter().map(|(t, d)| ResourceRecord {
+            r_name: name("example.com"),
+            r_type: t,
+            r_class: 1,
+            r_ttl: 60,
+            r_data: d
+        }).collect();
+
+        let bytes = request.response(&records).to_bytes().unwrap();
+        let msg = Message::new(&bytes).unwrap();
+
+        assert_eq!(records.len(), msg.answers().len());
+        for (a, b) in records.iter().zip(msg.answers().iter()) {
+            assert_eq!(a.r_type, b.r_type);
+            assert_eq!(a.r_data, b.r_data);
+            assert!(b.is_self_contained());
+        }
+
+        // names in the newer types are never compressed, so the SRV target
+        // can't point at the "example.com" written before it
+        let mut srv = Vec::new();
+        records[6].write(&mut srv).unwrap();
+        assert!(bytes.windows(srv.len() - 13).any(|w| w == &srv[13..]));
+    }
+
+    #[test]
+    fn unknown_types_and_classes() {
+        let mut bytes = include_bytes!("../test/dns_request.bin").to_vec();
+        let len = bytes.len();
+
+        for &(t, c) in [(0x1c, 0x01), (0x41, 0x03), (0xff00, 0xff), (0x1234, 0x4321)].iter() {
+            bytes[len - 4] = (t >> 8) as u8;
+            bytes[len - 3] = t as u8;
+            bytes[len - 2] = (c >> 8) as u8;
+            bytes[len - 1] = c as u8;
+
+            let msg = Message::new(&bytes).unwrap();
+            assert_eq!(t, msg.questions[0].q_type().code());
+            assert_eq!(c, msg.questions[0].q_class().code());
+            assert_eq!(bytes, msg.to_bytes().unwrap());
+        }
+
+        assert_eq!(QuestionType::AAAA, QuestionType::new(0x1c));
+        assert_eq!(QuestionType::Unknown(0x1234), QuestionType::new(0x1234));
+        assert_eq!(QuestionClass::CH, QuestionClass::new(0x03));
+        assert_eq!(QuestionClass::Unknown(0x4321), QuestionClass::new(0x4321));
+    }
+
+    #[test]
+    fn edns() {
+        let mut request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        assert_eq!(None, request.edns);
+        assert_eq!(512, request.max_payload());
+
+        request.edns = Some(Edns {
+            payload_size: 4096,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![(10, vec![1, 2, 3, 4, 5, 6, 7, 8])]
+        });
+        let bytes = request.to_bytes().unwrap();
+        // an OPT record with a root owner in the additional section
+        assert_eq!(&[0, 1], &bytes[10..12]);
+        assert_eq!(&[0, 0, 41, 0x10, 0, 0, 0, 0x80, 0, 0, 12, 0, 10, 0, 8],
+                   &bytes[bytes.len() - 23..bytes.len() - 8]);
+
+        let msg = Message::new(&bytes).unwrap();
+        assert_eq!(request.edns, msg.edns);
+        assert!(msg.additional().is_empty());
+        assert_eq!(4096, msg.max_payload());
+
+        let mut response = msg.response(&[]);
+        assert_eq!(Some(Edns { dnssec_ok: true, .. Edns::new(EDNS_PAYLOAD_SIZE) }), response.edns);
+
+        // BADVERS is 16, the upper bits go in the OPT record
+        response.set_return_code(0);
+        response.edns.as_mut().unwrap().extended_rcode = 1;
+        let msg = Message::new(&response.to_bytes().unwrap()).unwrap();
+        assert_eq!(16, msg.extended_return_code());
+
+        // a second OPT record is a format error
+        let mut twice = bytes.clone();
+        twice[11] = 2;
+        twice.extend(bytes[bytes.len() - 23..].iter().cloned());
+        assert!(Message::new(&twice).is_err());
+    }
+
+    #[test]
+    fn truncation() {
+        let response = Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap();
+        let truncated = response.truncated();
+
+        assert!(truncated.is_truncated());
+        assert_eq!(response.tx_id, truncated.tx_id);
+        assert_eq!(response.questions().len(), truncated.questions().len());
+        assert!(truncated.answers().is_empty());
+
+        let mut edns = Edns::new(100);
+        assert_eq!(512, Message { edns: Some(edns.clone()), .. Message::default() }.max_payload());
+        edns.payload_size = 1400;
+        assert_eq!(1400, Message { edns: Some(edns), .. Message::default() }.max_payload());
+    }
+
+    fn question_with_name(name: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        bytes.extend(name.iter().cloned());
+        bytes.extend([0, 1, 0, 1].iter().cloned());
+        bytes
+    }
+
+    #[test]
+    fn malformed_names() {
+        let mut label = vec![63];
+        label.extend([b'a'; 63].iter().cloned());
+
+        // the longest label and the longest name are fine
+        let mut name = label.clone();
+        name.push(0);
+        let msg = Message::new(&question_with_name(&name)).unwrap();
+        assert_eq!(63, msg.questions()[0].name().len());
+
+        let mut name = Vec::new();
+        for _ in 0..3 {
+            name.extend(label.iter().cloned());
+        }
+        name.extend([61].iter().chain([b'b'; 61].iter()).cloned());
+        name.push(0);
+        assert_eq!(255, name.len());
+        let msg = Message::new(&question_with_name(&name)).unwrap();
+        assert_eq!(253, msg.questions()[0].name().len());
+
+        // one byte more is too long
+        name[192] = 62;
+        name.insert(193, b'b');
+        assert!(Message::new(&question_with_name(&name)).is_err());
+
+        for name in [
+            // reserved label types
+            &[0x40, b'a', 0][..],
+            &[0x80, b'a', 0][..],
+            // pointing at itself, forwards and into a loop
+            &[0xc0, 12][..],
+            &[0xc0, 14, 0][..],
+            &[1, b'a', 0xc0, 12][..],
+            // no end
+            &[3, b'c', b'o', b'm'][..]
+        ].iter() {
+            let mut bytes = question_with_name(name);
+            if name[name.len() - 1] != 0 {
+                bytes.truncate(12 + name.len());
+            }
+            assert!(Message::new(&bytes).is_err(), "{:?}", name);
+        }
+
+        // a pointer into the question of an answer that points back into
+        // its own labels, looping forever if followed
+        let mut bytes = question_with_name(&[1, b'a', 0]);
+        bytes[7] = 1;
+        bytes.extend([1, b'b', 0xc0, 19, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0].iter().cloned());
+        assert!(Message::new(&bytes).is_err());
+    }
+
+    #[test]
+    fn dotted_labels() {
+        // a DNS-SD instance name, "a.b" is one label
+        let request = question_with_name(b"\x03a.b\x05local\x00");
+        assert!(Message::new(&request).is_err());
+
+        let request = MessageRef::new(&request).unwrap();
+        assert!(request.questions().next().unwrap().is_ok());
+        assert_eq!(512, request.max_payload());
+
+        let mut answer = question_with_name(b"\x03A.B\x05LOCAL\x00");
+        answer[2] |= 0x80;
+        assert!(MessageRef::new(&answer).unwrap().answers_request(&request));
+
+        // the same dotted form split into labels is a different name
+        let other = question_with_name(b"\x01a\x01b\x05local\x00");
+        assert!(!MessageRef::new(&other).unwrap().answers_request(&request));
+
+        // names are still checked when skipped
+        let looping = question_with_name(&[1, b'a', 0xc0, 12]);
+        assert!(MessageRef::new(&looping).unwrap().questions().next().unwrap().is_err());
+    }
+
+    /// A query for "a" A IN with `answer` in its answer section, which
+    /// starts at offset 19.
+    fn answer_after_question(answer: &[u8]) -> Vec<u8> {
+        let mut bytes = question_with_name(&[1, b'a', 0]);
+        bytes[7] = 1;
+        bytes.extend(answer.iter().cloned());
+        bytes
+    }
+
+    #[test]
+    fn error_answers() {
+        let request = include_bytes!("../test/dns_request.bin");
+        let mut bytes = Vec::new();
+        MessageRef::new(request).unwrap().write_error(Rcode::ServFail, &mut bytes).unwrap();
+
+        let msg = Message::new(&bytes).unwrap();
+        let query = Message::new(request).unwrap();
+        assert!(msg.is_response());
+        assert_eq!(query.tx_id, msg.tx_id);
+        assert_eq!(Rcode::ServFail, msg.rcode());
+        assert_eq!(1, msg.questions().len());
+        assert_eq!(query.questions()[0].name(), msg.questions()[0].name());
+        assert!(msg.answers().is_empty());
+
+        // nothing past the header can be read
+        let mut garbage = request[..12].to_vec();
+        garbage.extend([0xc0, 0xff].iter().cloned());
+        bytes.clear();
+        MessageRef::new(&garbage).unwrap().write_error(Rcode::FormErr, &mut bytes).unwrap();
+
+        let msg = Message::new(&bytes).unwrap();
+        assert_eq!(query.tx_id, msg.tx_id);
+        assert_eq!(Rcode::FormErr, msg.rcode());
+        assert!(msg.questions().is_empty());
+    }
+
+    #[test]
+    fn fuzz_regressions() {
+        // a dot inside a label, it came back as a different name
+        let bytes = [0x7f, 0xcd, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x02, 0x00, 0x2e, 0x00, 0x00, 0x00, 0x00, 0x00,
+                     0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00];
+        assert!(Message::new(&bytes).is_err());
+
+        // an owner name pointing at its own start, it hung the parser
+        let looping = answer_after_question(&[1, b'b', 0xc0, 19, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(Message::new(&looping).is_err());
+        assert!(MessageRef::new(&looping).unwrap().records().next().unwrap().is_err());
+
+        // a pointer past the name it ends, into the record's own fields
+        let forward = answer_after_question(&[0xc0, 21, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(Message::new(&forward).is_err());
+        assert!(MessageRef::new(&forward).unwrap().records().next().unwrap().is_err());
+
+        // labels short enough on their own that only grow past 255 bytes
+        // through a pointer, the name overflowed its buffer
+        let mut long = vec![63];
+        long.extend([b'a'; 63].iter().cloned());
+        let mut name = Vec::new();
+        for _ in 0..3 {
+            name.extend(long.iter().cloned());
+        }
+        name.push(0);
+        let mut bytes = question_with_name(&name);
+        bytes[7] = 1;
+        bytes.extend(long.iter().cloned());
+        bytes.extend([0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0].iter().cloned());
+        assert!(Message::new(&bytes).is_err());
+        assert!(MessageRef::new(&bytes).unwrap().records().next().unwrap().is_err());
+
+        // a label of just a dot, dotted it had an empty label in the middle
+        // that couldn't be encoded again
+        let bytes = question_with_name(&[1, b'a', 1, b'.', 1, b'b', 0]);
+        assert!(Message::new(&bytes).is_err());
+    }
+
+    #[test]
+    fn flags() {
+        let mut msg = Message::default();
+        assert!(msg.is_query());
+
+        msg.set_response(true);
+        msg.set_auth_answer(true);
+        msg.set_truncated(true);
+        msg.set_recursion_desired(true);
+        msg.set_recursion_available(true);
+        msg.set_authentic_data(true);
+        msg.set_checking_disabled(true);
+        msg.set_opcode(Opcode::Notify);
+        msg.set_rcode(Rcode::Refused);
+        assert_eq!(0b1_0100_1_1_1_1_0_1_1_0101, msg.flags);
+
+        msg.set_truncated(false);
+        msg.set_checking_disabled(false);
+        assert_eq!(0b1_0100_1_0_1_1_0_1_0_0101, msg.flags);
+        assert!(msg.is_response());
+        assert!(msg.is_auth_answer());
+        assert!(!msg.is_truncated());
+        assert!(msg.authentic_data());
+        assert_eq!(Opcode::Notify, msg.opcode());
+        assert_eq!(Rcode::Refused, msg.rcode());
+
+        msg.set_opcode(Opcode::Unknown(15));
+        assert_eq!(Opcode::Unknown(15), msg.opcode());
+        assert_eq!(Rcode::Refused, msg.rcode());
+    }
+
+    #[test]
+    fn builder() {
+        let query = MessageBuilder::query(0xbeef)
+            .question("example.com", QuestionType::AAAA, QuestionClass::IN)
+            .edns(Some(Edns::new(4096)))
+            .checking_disabled(true)
+            .build();
+
+        let parsed = Message::new(&query.to_bytes().unwrap()).unwrap();
+        assert_eq!(0xbeef, parsed.tx_id);
+        assert!(parsed.is_query());
+        assert!(parsed.recursion_desired());
+        assert!(parsed.checking_disabled());
+        assert_eq!("example.com", parsed.questions()[0].name());
+        assert_eq!(QuestionType::AAAA, parsed.questions()[0].q_type());
+        assert_eq!(4096, parsed.max_payload());
+
+        let response = MessageBuilder::response(&parsed)
+            .authority(ResourceRecord {
+                r_name: "example.com".bytes().collect(),
+                r_type: 6,
+                r_class: 1,
+                r_ttl: 300,
+                r_data: ResourceData::SOA {
+                    mname: "ns.example.com".bytes().collect(),
+                    rname: "hostmaster.example.com".bytes().collect(),
+                    serial: 1,
+                    refresh: 2,
+                    retry: 3,
+                    expire: 4,
+                    minimum: 5
+                }
+            })
+            .auth_answer(true)
+            .rcode(Rcode::BadVers)
+            .build();
+
+        let parsed = Message::new(&response.to_bytes().unwrap()).unwrap();
+        assert_eq!(0xbeef, parsed.tx_id);
+        assert!(parsed.is_response());
+        assert!(parsed.is_auth_answer());
+        assert!(parsed.recursion_available());
+        assert_eq!(Rcode::BadVers, parsed.rcode());
+        assert_eq!(1, parsed.authority().len());
+        assert!(parsed.answers().is_empty());
+    }
+
+    #[test]
+    fn message_ref() {
+        let bytes = include_bytes!("../test/referral_response.bin");
+        let msg = Message::new(bytes).unwrap();
+        let view = MessageRef::new(bytes).unwrap();
+
+        assert_eq!(msg.tx_id, view.tx_id());
+        assert_eq!(msg.flags, view.flags());
+        assert!(view.is_response());
+        assert_eq!(Rcode::NoError, view.rcode());
+
+        let questions: Vec<QuestionRef> = view.questions().map(|q| q.unwrap()).collect();
+        assert_eq!(1, questions.len());
+        assert_eq!("example.com".as_bytes(), &questions[0].name.to_name().unwrap()[..]);
+        assert_eq!(QuestionType::A, questions[0].q_type);
+        assert!(view.answers_question(&msg));
+
+        let records: Vec<RecordRef> = view.records().map(|r| r.unwrap()).collect();
+        let sections: Vec<Section> = records.iter().map(|r| r.section).collect();
+        assert_eq!(vec![Section::Answer, Section::Authority, Section::Authority,
+                        Section::Additional, Section::Additional], sections);
+
+        let all = msg.answers().iter().chain(msg.authority().iter()).chain(msg.additional().iter());
+        for (r, owned) in records.iter().zip(all) {
+            assert!(r.name.eq_name(&owned.r_name));
+            assert_eq!(owned.r_type, r.r_type);
+            assert_eq!(owned.r_ttl, r.r_ttl);
+        }
+        assert_eq!(bytes.len(), records[4].end);
+        assert_eq!(&[199, 43, 133, 53], records[4].rdata);
+        assert!(view.edns().is_none());
+
+        let other = MessageBuilder::query(msg.tx_id)
+            .question("Example.COM", QuestionType::A, QuestionClass::IN)
+            .build();
+        assert!(view.answers_question(&other));
+        let other = MessageBuilder::query(msg.tx_id)
+            .question("example.org", QuestionType::A, QuestionClass::IN)
+            .build();
+        assert!(!view.answers_question(&other));
+
+        // records running past the end of the packet
+        let cut = MessageRef::new(&bytes[..bytes.len() - 2]).unwrap();
+        assert!(cut.records().last().unwrap().is_err());
+        assert!(MessageRef::new(&bytes[..11]).is_err());
+    }
+
+    #[test]
+    fn message_ref_edns() {
+        let mut response = Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap();
+        let plain = response.to_bytes().unwrap();
+        response.edns = Some(Edns { extended_rcode: 1, .. Edns::new(4096) });
+        let bytes = response.to_bytes().unwrap();
+
+        let view = MessageRef::new(&bytes).unwrap();
+        assert_eq!(4096, view.edns().unwrap().r_class);
+        assert_eq!(Rcode::BadVers, view.rcode());
+
+        // dropping the OPT record gets us back to the plain message
+        let mut stripped = Vec::new();
+        view.write_with_edns(None, &mut stripped).unwrap();
+        assert_eq!(plain, stripped);
+
+        // the extended return code stays with the answer
+        let mut swapped = Vec::new();
+        view.write_with_edns(Some(&Edns::new(1232)), &mut swapped).unwrap();
+        let msg = Message::new(&swapped).unwrap();
+        assert_eq!(Some(Edns { extended_rcode: 1, .. Edns::new(1232) }), msg.edns);
+        assert_eq!(Rcode::BadVers, msg.rcode());
+        assert_eq!(4, msg.answers().len());
+
+        let mut truncated = Vec::new();
+        view.write_truncated(None, &mut truncated).unwrap();
+        let mut expected = response.truncated();
+        expected.edns = None;
+        assert_eq!(expected.to_bytes().unwrap(), truncated);
+
+        // with a record after the OPT one it can't be cut out
+        let first = view.records().next().unwrap().unwrap();
+        let mut reordered = bytes.clone();
+        reordered.extend(bytes[first.start..first.end].iter().cloned());
+        reordered[11] += 1;
+
+        let view = MessageRef::new(&reordered).unwrap();
+        assert!(view.records().all(|r| r.is_ok()));
+        match view.write_with_edns(None, &mut Vec::new()) {
+            Err(Error::Encode) => (),
+            r => panic!("{:?}", r)
+        }
+    }
 }
@@ -1,6 +1,7 @@
 use std::io;
 use std::net;
 use dns;
+use byteorder;
 #[derive (Debug)]
 pub enum Error {
     QueryStateError,
@@ -29,6 +30,12 @@ impl From<dns::Error> for Error {
     }
 }
 
+impl From<byteorder::Error> for Error {
+    fn from(b: byteorder::Error) -> Error {
+        Error::Io(io::Error::from(b))
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(io: io::Error) -> Error {
         Error::Io(io)
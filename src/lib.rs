@@ -0,0 +1,9 @@
+//! The DNS wire format, exposed on its own for the fuzz targets in `fuzz/`.
+#![allow(dead_code)]
+extern crate byteorder;
+extern crate arrayvec;
+extern crate smallvec;
+extern crate time;
+
+pub mod dns;
+pub mod text;
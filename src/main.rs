@@ -13,27 +13,33 @@ extern crate env_logger;
 extern crate chan;
 extern crate chan_signal;
 extern crate time;
+extern crate toml;
 
 mod errors;
 mod dns;
 mod buf;
 mod datagram;
+mod connection;
 mod cache;
 mod users;
 mod query;
 mod server;
-mod lib;
+mod control;
+mod text;
+mod config;
 
 use chan_signal::Signal;
 use getopts::{Matches, Options};
 use std::env;
 use libc::{setuid, setgid, fork, setsid};
-use mio::udp::UdpSocket;
 use users::get_ids;
 use getopts::Fail;
+use std::path::Path;
+use std::io::{self, Write};
+use std::fmt;
 
-fn drop_priv(args: &Matches) -> Result<(), &'static str> {
-    let (user, group) = match (args.opt_str("user"), args.opt_str("group")) {
+fn drop_priv(config: &config::Config) -> Result<(), &'static str> {
+    let (user, group) = match (config.user.as_ref(), config.group.as_ref()) {
         (Some(u), Some(g)) => (u,g),
         _ => return Ok(())
     };
@@ -80,9 +86,20 @@ fn detach() -> bool {
 fn parse_opts() -> Result<Matches, Fail> {
     let mut opts = Options::new();
 
+    opts.optopt("c", "config", "file to read the configuration from", "PATH");
     opts.optflag("d", "daemonize", "run this in the background");
     opts.optopt("u", "user", "user to become", "USER");
     opts.optopt("g", "group", "group to become", "GROUP");
+    opts.optmulti("l", "listen", "address to serve DNS on, udp: or tcp: in front for only one of them, may be repeated", "ADDR");
+    opts.optmulti("", "upstream", "server to pass questions on to, port 53 unless given, may be repeated", "ADDR");
+    opts.optopt("", "control", "address to serve the cache control interface on", "ADDR");
+    opts.optopt("", "min-ttl", "lowest TTL cached records are kept for", "SECONDS");
+    opts.optopt("", "max-ttl", "highest TTL cached records are kept for", "SECONDS");
+    opts.optopt("", "stale-window", "how long expired records may be served while upstreams fail", "SECONDS");
+    opts.optopt("", "prefetch-hits", "hits after which entries are refreshed before expiring, 0 disables", "COUNT");
+    opts.optopt("", "cache-file", "file the cache is saved to and loaded from", "PATH");
+    opts.optopt("", "cache-entries", "most entries the cache holds", "COUNT");
+    opts.optopt("", "cache-bytes", "most memory the cache uses", "BYTES");
     opts.optflag("h", "help", "print this help menu");
 
     let matches = try!(opts.parse(env::args()));
@@ -97,28 +114,69 @@ fn parse_opts() -> Result<Matches, Fail> {
 
 }
 
+fn exit_with_error<E: fmt::Display>(e: E) -> ! {
+    let _ = writeln!(io::stderr(), "{}", e);
+    std::process::exit(1);
+}
+
 pub fn main() {
     let signal = chan_signal::notify(&[Signal::INT, Signal::TERM]);
 
     env_logger::init().unwrap();
 
-    let args = parse_opts().ok().expect("option parsing error!");
+    let args = match parse_opts() {
+        Ok(args) => args,
+        Err(e) => exit_with_error(e)
+    };
+
+    let config = match args.opt_str("config") {
+        Some(path) => match config::Config::load(Path::new(&path)) {
+            Ok(c) => c,
+            Err(e) => exit_with_error(format!("error in {}: {}", path, e))
+        },
+        None => config::Config::default()
+    };
+
+    // the command line wins over the file
+    let options: Vec<(&str, Vec<String>)> = config::OPTIONS.iter()
+        .map(|&option| (option, args.opt_strs(option)))
+        .filter(|&(_, ref values)| !values.is_empty())
+        .collect();
+    let config = match config.override_with(&options) {
+        Ok(c) => c,
+        Err(e) => exit_with_error(e)
+    };
+    if let Err(e) = config.check(&options) {
+        exit_with_error(e);
+    }
 
     if args.opt_present("daemonize") && detach() {
         return;
     }
 
-    let addr = "127.0.0.1:9000".parse().unwrap();
-
-    info!("Listening on {}", addr);
+    let mut listeners = Vec::new();
+    for spec in config.listen.iter() {
+        match server::Listener::bind(spec) {
+            Ok(l) => listeners.extend(l),
+            Err(e) => exit_with_error(format!("can't listen on {}: {}", spec, e))
+        }
+        info!("Listening on {}", spec);
+    }
 
-    let server = UdpSocket::bound(&addr).unwrap();
+    if listeners.len() > server::MAX_LISTENERS {
+        exit_with_error(format!("can't listen on more than {} sockets", server::MAX_LISTENERS));
+    }
 
-    if let Err(_) = drop_priv(&args) {
+    if let Err(_) = drop_priv(&config) {
         panic!("Can't drop privileges exiting...");
     }
 
-    let (thr, channel, end_rx) = server::run_server(server);
+    let (thr, channel, end_rx) = server::run_server(listeners, config.server);
+
+    if let Some(addr) = config.control {
+        control::run_control(&addr, channel.clone()).ok().expect("control interface failed");
+        info!("Control interface on {}", addr);
+    }
 
     chan_select! {
         signal.recv() -> signal => {
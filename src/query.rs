@@ -2,16 +2,20 @@ use dns::*;
 use errors;
 use std::net::SocketAddr;
 use mio::{Token, EventSet, Timeout};
-use buf::*;
+use buf::BUF_LEN;
 use std::io::{Write};
 use mio::udp::UdpSocket;
 use datagram::*;
 use arrayvec::*;
 use time;
-use std::io;
 use std::fmt;
 use cache::*;
 
+/// Most upstreams a query is sent to.
+pub const MAX_UPSTREAMS: usize = 16;
+/// Largest answer for TCP clients, the most its length prefix can say.
+const TCP_PAYLOAD_LIMIT: usize = 0xffff;
+
 #[derive (Debug, Copy, Clone, PartialEq)]
 enum QueryPhase {
     Waiting,
@@ -23,19 +27,33 @@ enum QueryPhase {
 #[derive (Debug)]
 struct Upstream {
     token: Token,
-    answer: Message,
     phase: QueryPhase,
     start_time: f64,
     end_time: f64
 }
 
+/// Where a query came from and its answer goes to.
+#[derive (Debug, Copy, Clone, PartialEq)]
+pub enum Client {
+    /// the token of the listener and the client's address
+    Udp(Token, SocketAddr),
+    /// the token of the TCP connection
+    Tcp(Token),
+    /// nobody, the query refreshes the cache
+    Prefetch
+}
+
 pub struct Query {
     token: Token,
+    /// the request, unless it had to be passed on opaquely
     message: Option<Message>,
-    addr: Option<SocketAddr>,
-    bytes: ByteBuf,
-    upstreams: ArrayVec<[Upstream;16]>,
-    timeout: Option<Timeout>
+    client: Client,
+    /// the request to send upstream, then the answer for the client
+    bytes: Vec<u8>,
+    upstreams: ArrayVec<[Upstream;MAX_UPSTREAMS]>,
+    timeout: Option<Timeout>,
+    /// upstreams to ask if the first ones are slow to answer
+    fallback: Option<(Timeout, Vec<SocketAddr>)>
 }
 
 impl fmt::Debug for Query {
@@ -48,21 +66,21 @@ impl Query {
     pub fn new(token: Token) -> Query {
         Query {
             token: token,
-            bytes: ByteBuf::new(),
+            bytes: Vec::new(),
             message: None,
-            addr: None,
+            client: Client::Prefetch,
             upstreams: ArrayVec::new(),
-            timeout: None
+            timeout: None,
+            fallback: None
         }
     }
 
-    pub fn rx(&mut self, s: &UdpSocket) -> Result<Option<()>, errors::Error> {
-        self.bytes.set_writable();
-        match try!(s.recv_from(self.bytes.mut_bytes())) {
+    pub fn rx(&mut self, listener: Token, s: &UdpSocket) -> Result<Option<()>, errors::Error> {
+        let mut buf = [0u8; BUF_LEN];
+        match try!(s.recv_from(&mut buf)) {
             Some((size, addr)) => {
-                self.bytes.set_pos(size as i32);
-                self.message = Some(try!(Message::new(self.bytes.bytes())));
-                self.addr = Some(addr);
+                self.client = Client::Udp(listener, addr);
+                try!(self.read_request(&buf[..size]));
                 Ok(Some(()))
             },
             None => {
@@ -71,22 +89,223 @@ impl Query {
         }
     }
 
-    pub fn answer_in_cache(&self, cache: &Cache) -> bool {
-        let m = self.message.as_ref().unwrap();
+    /// Sets the query up with a request read from TCP connection `conn`.
+    pub fn tcp_request(&mut self, conn: Token, bytes: &[u8]) -> Result<(), errors::Error> {
+        self.client = Client::Tcp(conn);
+        self.read_request(bytes)
+    }
+
+    /// Takes a client's request. One whose names can't be held by a
+    /// `Message`, like the DNS-SD instance names with dots inside labels
+    /// (RFC 6763 4.3), is passed upstream as it came in and its answer
+    /// goes back the same way, the cache never sees either.
+    fn read_request(&mut self, b: &[u8]) -> Result<(), errors::Error> {
+        let e = match Message::new(b) {
+            Ok(m) => {
+                self.message = Some(m);
+                return self.write_request()
+            },
+            Err(e) => e
+        };
+
+        let request = try!(MessageRef::new(b));
+        if request.is_response() || request.question_count() != 1 {
+            return Err(errors::Error::from(e))
+        }
+        for q in request.questions() {
+            try!(q);
+        }
+        for r in request.records() {
+            try!(r);
+        }
+
+        self.message = None;
+        self.bytes = b.to_vec();
+        Ok(())
+    }
+
+    pub fn message(&self) -> Option<&Message> {
+        self.message.as_ref()
+    }
+
+    /// Prefetch queries are made by us to refresh the cache, there is no
+    /// client to send the answer to.
+    pub fn is_prefetch(&self) -> bool {
+        self.client == Client::Prefetch
+    }
+
+    pub fn client(&self) -> Client {
+        self.client
+    }
+
+    /// The most we may answer with, over TCP only the framing limits it.
+    fn payload_limit(&self) -> Result<usize, errors::Error> {
+        match (self.client, self.message.as_ref()) {
+            (Client::Tcp(_), _) => Ok(TCP_PAYLOAD_LIMIT),
+            (_, Some(m)) => Ok(m.max_payload()),
+            // an opaque request stays in the buffer until it's answered
+            (_, None) => Ok(try!(MessageRef::new(&self.bytes)).max_payload())
+        }
+    }
+
+    /// Sets the query up to ask upstream the same question as `m`.
+    pub fn prefetch(&mut self, m: &Message) -> Result<(), errors::Error> {
+        self.message = Some(m.clone());
+        self.write_request()
+    }
+
+    /// Writes the question to ask upstream into the query's buffer, it
+    /// advertises our own payload size whatever the client's was.
+    fn write_request(&mut self) -> Result<(), errors::Error> {
+        let request = {
+            let m = try!(self.message.as_ref().ok_or("no message!"));
+            let mut request = m.clone();
+            request.edns = Some(Edns {
+                dnssec_ok: m.edns.as_ref().map_or(false, |e| e.dnssec_ok),
+                .. Edns::new(EDNS_PAYLOAD_SIZE)
+            });
+            request
+        };
+
+        self.bytes = try!(request.to_bytes());
+        Ok(())
+    }
+
+    /// Writes the response for the client into the query's buffer, only
+    /// the question is kept if it doesn't fit their payload size. Like
+    /// every response it replaces the question only once fully encoded,
+    /// the question is still needed for other upstreams if this fails.
+    fn write_response(&mut self, response: &Message) -> Result<(), errors::Error> {
+        let limit = try!(self.payload_limit());
+        let mut bytes = try!(response.to_bytes());
+
+        if bytes.len() > limit {
+            bytes = try!(response.truncated().to_bytes());
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    /// Passes an upstream answer on to the client as it came in, only
+    /// its OPT record is swapped for ours. It is cut down to the question
+    /// if it's more than the client takes, however big it came in.
+    fn respond_upstream(&mut self, raw: &[u8]) -> Result<(), errors::Error> {
+        let answer = try!(MessageRef::new(raw));
+        let limit = try!(self.payload_limit());
+
+        if self.message.is_none() {
+            let mut bytes = raw.to_vec();
+            if bytes.len() > limit {
+                bytes.clear();
+                try!(answer.write_truncated(None, &mut bytes));
+            }
+            self.bytes = bytes;
+            return Ok(())
+        }
+
+        let edns = {
+            let m = try!(self.message.as_ref().ok_or("no message!"));
+            m.edns.as_ref().map(|e| e.response())
+        };
+
+        // the common case, the answer's extended return code is carried
+        // over in the same walk
+        let mut bytes = Vec::with_capacity(raw.len());
+        match answer.write_with_edns(edns.as_ref(), &mut bytes) {
+            Ok(()) if bytes.len() <= limit => {
+                self.bytes = bytes;
+                return Ok(())
+            },
+            Ok(()) => (),
+            // the OPT record isn't last, it has to be encoded again
+            Err(Error::Encode) => {
+                let mut response = try!(answer.to_message());
+                let self_contained = response.answers().iter()
+                    .chain(response.authority().iter())
+                    .chain(response.additional().iter())
+                    .all(|r| r.is_self_contained());
+
+                if self_contained {
+                    let extended_rcode = response.edns.as_ref().map_or(0, |e| e.extended_rcode);
+                    response.edns = edns.map(|e| Edns { extended_rcode: extended_rcode, .. e });
+                    return self.write_response(&response)
+                }
+            },
+            Err(e) => return Err(errors::Error::from(e))
+        }
+
+        let extended_rcode = answer.edns().map_or(0, |r| ((r.r_ttl as u32) >> 24) as u8);
+        let edns = edns.map(|e| Edns { extended_rcode: extended_rcode, .. e });
+        bytes.clear();
+        try!(answer.write_truncated(edns.as_ref(), &mut bytes));
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    pub fn cache_key(&self) -> Option<Key> {
+        self.message.as_ref()
+            .and_then(|m| m.questions().first())
+            .map(Key::for_question)
+    }
 
-        let questions = m.questions();
+    /// Looks the question up in the cache and on a hit writes the response
+    /// into the query's buffer, ready to be sent back to the client.
+    pub fn answer_from_cache(&mut self, cache: &mut Cache) -> Result<bool, errors::Error> {
+        match self.cache_key().and_then(|key| cache.get(&key)) {
+            Some(answer) => self.respond(answer).map(|_| true),
+            None => Ok(false)
+        }
+    }
 
-        if let Some(q) = questions.first() {
-            if let Some(record) = cache.get(&q.name()) {
-                return true
+    /// Answers a request for an EDNS version other than 0 with BADVERS
+    /// (RFC 6891 6.1.3), it isn't looked up or passed on.
+    pub fn answer_bad_version(&mut self) -> Result<bool, errors::Error> {
+        let response = {
+            // opaque requests are left for the upstreams to turn down
+            let m = match self.message {
+                Some(ref m) => m,
+                None => return Ok(false)
+            };
+            match m.edns {
+                Some(ref edns) if edns.version > 0 => (),
+                _ => return Ok(false)
             }
 
-            return false
-        } else {
-            panic!("shouldn't get here")
+            let mut response = m.response(&[]);
+            response.set_rcode(Rcode::BadVers);
+            response
+        };
+
+        self.write_response(&response).map(|_| true)
+    }
+
+    /// Same as `answer_from_cache` but willing to use expired records, for
+    /// when the upstreams failed to answer.
+    pub fn answer_stale(&mut self, cache: &mut Cache) -> Result<bool, errors::Error> {
+        match self.cache_key().and_then(|key| cache.get_stale(&key)) {
+            Some(answer) => self.respond(answer).map(|_| true),
+            None => Ok(false)
         }
     }
 
+    fn respond(&mut self, answer: Answer) -> Result<(), errors::Error> {
+        let response = {
+            let m = try!(self.message.as_ref().ok_or("no message!"));
+
+            match answer {
+                Answer::Records(records) => m.response(&records),
+                Answer::Negative(rcode, cnames, soa) => {
+                    let mut response = m.response(&cnames);
+                    response.set_return_code(rcode);
+                    response.name_server.push(soa);
+                    response
+                }
+            }
+        };
+
+        self.write_response(&response)
+    }
+
     pub fn set_timeout(&mut self, t: Timeout) {
         self.timeout = Some(t);
     }
@@ -95,12 +314,19 @@ impl Query {
         self.timeout.take()
     }
 
-    pub fn upstream_tokens(&self) -> Vec<Token> {
-        self.upstreams.iter().map(|up| up.token ).collect()
+    pub fn set_fallback(&mut self, t: Timeout, upstreams: Vec<SocketAddr>) {
+        self.fallback = Some((t, upstreams));
     }
 
-    pub fn rx_buf(&mut self) -> &mut [u8] {
-        self.bytes.mut_bytes()
+    pub fn take_fallback(&mut self) -> (Option<Timeout>, Vec<SocketAddr>) {
+        match self.fallback.take() {
+            Some((t, upstreams)) => (Some(t), upstreams),
+            None => (None, Vec::new())
+        }
+    }
+
+    pub fn upstream_tokens(&self) -> Vec<Token> {
+        self.upstreams.iter().map(|up| up.token ).collect()
     }
 
     fn find_upstream(&self, t: Token) -> Option<usize> {
@@ -108,7 +334,10 @@ impl Query {
     }
 
     pub fn get_addr(&self) -> Option<&SocketAddr> {
-        self.addr.as_ref()
+        match self.client {
+            Client::Udp(_, ref addr) => Some(addr),
+            _ => None
+        }
     }
 
     fn send_request_phase(&mut self, datagram: &mut Datagram, event_response: EventResponse) -> Result<bool, errors::Error> {
@@ -119,7 +348,9 @@ impl Query {
                 } else {
                     Ok(false)
                 }
-            }
+            },
+            // a TCP connection still being made or written to
+            EventResponse::Tx(None) if datagram.is_tcp() => Ok(false),
             _ => {
                 try!(Err("invalid response!"))
             }
@@ -129,15 +360,24 @@ impl Query {
     fn wait_response_phase(&mut self, datagram: &mut Datagram, event_response: EventResponse) -> Result<bool, errors::Error> {
         match event_response {
             EventResponse::Rx(Some(addr)) => {
-                let upstream = try!(self.find_upstream(datagram.token()).ok_or("no upstream for datagram!"));
-                self.upstreams[upstream].answer = try!(Message::new(datagram.get_ref()));
+                let answer = try!(MessageRef::new(datagram.get_ref()));
+                let (tx_id, same_question) = match self.message {
+                    Some(ref m) => (m.tx_id, answer.answers_question(m)),
+                    None => {
+                        let request = try!(MessageRef::new(&self.bytes));
+                        (request.tx_id(), answer.answers_request(&request))
+                    }
+                };
 
-                let tx_id = try!(self.message.as_ref().ok_or("no message!")).tx_id;
-                if tx_id != self.upstreams[upstream].answer.tx_id {
+                if tx_id != answer.tx_id() {
                     try!(Err("invalid tx_id!"));
                 }
+                if !answer.is_response() || !same_question {
+                    try!(Err("answer for a different question!"));
+                }
                 Ok(true)
             },
+            EventResponse::Rx(None) if datagram.is_tcp() => Ok(false),
             _ => {
                 try!(Err("invalid response!"))
             }
@@ -154,7 +394,7 @@ impl Query {
         match self.upstreams[upstream].phase {
             QueryPhase::SendRequest => {
                 // Send To Upstream Server
-                assert!(events.is_writable());
+                assert!(events.is_writable() || datagram.is_tcp());
                 return self.send_request_phase(datagram, event_response).and_then(|success| {
                     if success {
                         // transition to next state
@@ -165,13 +405,19 @@ impl Query {
                 })
             },
             QueryPhase::WaitResponse => {
-                assert!(events.is_readable());
+                assert!(events.is_readable() || datagram.is_tcp());
                 return self.wait_response_phase(datagram, event_response).and_then(|success| {
-                    if success {
+                    if success && !datagram.is_tcp() && try!(MessageRef::new(datagram.get_ref())).is_truncated() {
+                        // ask the same upstream again over TCP for all of it
+                        debug!("[{:?}] truncated answer, retrying over tcp", self.token);
+                        try!(datagram.retry_tcp(self.question_bytes()));
+                        self.upstreams[upstream].phase = QueryPhase::SendRequest;
+                        Ok(false)
+                    } else if success {
                         self.upstreams[upstream].end_time = time::precise_time_s();
                         self.upstreams[upstream].phase = QueryPhase::ResponseReady;
                         datagram.set_idle();
-                        try!(self.copy_message_bytes(datagram.get_ref()));
+                        try!(self.respond_upstream(datagram.get_ref()));
                         Ok(true)
                     } else {
                         Ok(false)
@@ -188,19 +434,13 @@ impl Query {
         Err(errors::Error::String("ARG"))
     }
 
-    pub fn copy_message_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
-        self.bytes.set_writable();
-        self.bytes.write_all(bytes)
-    }
-
     pub fn question_bytes(&self) -> &[u8] {
-        self.bytes.bytes()
+        &self.bytes
     }
 
     pub fn add_upstream_token(&mut self, t: Token) {
         let upstream = Upstream{
             token: t,
-            answer: Message::default(),
             phase: QueryPhase::SendRequest,
             start_time: time::precise_time_s(),
             end_time: 0.0
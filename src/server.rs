@@ -1,48 +1,317 @@
 use mio::udp::*;
+use mio::tcp::TcpListener;
 use errors::*;
 use mio::util::*;
 use mio::{Token, EventLoop, EventSet, Handler, PollOpt};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use query::*;
 use datagram::*;
+use connection::*;
 use std::collections::VecDeque;
 use std::thread;
 use mio;
 use chan;
+use dns::{MessageRef, Rcode};
 use cache::*;
+use cache;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::fmt;
+use std::cmp;
 
-const SERVER: Token = Token(1);
+/// Listeners get the tokens below this, one each.
+pub const MAX_LISTENERS: usize = 16;
+
+/// How often expired entries are purged from the cache.
+const CACHE_SWEEP_MS: u64 = 30 * 1000;
+/// How often the cache is written out when a cache file is configured.
+const CACHE_SAVE_MS: u64 = 5 * 60 * 1000;
+/// How long upstreams get to answer unless configured.
+const QUERY_TIMEOUT_MS: u64 = 10 * 1000;
+/// How long a TCP connection may sit without queries before we close it.
+const TCP_IDLE_MS: u64 = 10 * 1000;
+/// How long upstreams of the preferred address family get to answer before
+/// the others are asked as well (RFC 8305).
+const FALLBACK_MS: u64 = 250;
+
+#[derive (Debug, Clone, Copy)]
+pub enum Timer {
+    Query(Token),
+    /// the query's upstreams of the other address family are due
+    Fallback(Token),
+    Connection(Token),
+    CacheSweep,
+    CacheSave
+}
+
+#[derive (Debug, Clone)]
+pub struct Config {
+    pub cache: cache::Options,
+    /// where the cache is kept across restarts
+    pub cache_file: Option<PathBuf>,
+    /// the servers questions are passed on to
+    pub upstreams: Vec<SocketAddr>,
+    pub query_timeout_ms: u64,
+    pub fallback_ms: u64,
+    /// queries being answered at once, prefetches included
+    pub max_queries: usize,
+    pub max_connections: usize,
+    pub tcp_idle_ms: u64
+}
+
+impl Config {
+    pub fn default() -> Config {
+        Config {
+            cache: cache::Options::default(),
+            cache_file: None,
+            upstreams: vec!["8.8.8.8:53".parse().unwrap(), "8.8.4.4:53".parse().unwrap()],
+            query_timeout_ms: QUERY_TIMEOUT_MS,
+            fallback_ms: FALLBACK_MS,
+            max_queries: NUM_CONCURRENT_QUERIES,
+            max_connections: NUM_CONNECTIONS,
+            tcp_idle_ms: TCP_IDLE_MS
+        }
+    }
+}
+
+fn is_ipv6(addr: &SocketAddr) -> bool {
+    match *addr {
+        SocketAddr::V6(..) => true,
+        SocketAddr::V4(..) => false
+    }
+}
+
+/// Reads a listen address as taken by `Listener::bind`, gives whether to
+/// serve UDP and TCP on it.
+pub fn parse_listen(spec: &str) -> Result<(bool, bool, SocketAddr), Error> {
+    let (udp, tcp, addr) = if spec.starts_with("udp:") {
+        (true, false, &spec[4..])
+    } else if spec.starts_with("tcp:") {
+        (false, true, &spec[4..])
+    } else {
+        (true, true, spec)
+    };
+    Ok((udp, tcp, try!(addr.parse())))
+}
+
+/// Reads an upstream's address, the port defaults to 53.
+pub fn parse_upstream(s: &str) -> Result<SocketAddr, Error> {
+    match s.parse() {
+        Ok(addr) => Ok(addr),
+        Err(e) => match s.parse::<IpAddr>() {
+            Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+            Err(_) => Err(Error::from(e))
+        }
+    }
+}
+
+fn save_cache(cache: &Cache, path: &Path) -> Result<usize, Error> {
+    let mut bytes = Vec::new();
+    let count = try!(cache.save(&mut bytes));
+    try!(write_cache(&bytes, path));
+    Ok(count)
+}
+
+fn write_cache(bytes: &[u8], path: &Path) -> Result<(), Error> {
+    // write to the side so a crash halfway doesn't lose the previous file
+    let tmp = path.with_extension("tmp");
+    try!(try!(File::create(&tmp)).write_all(bytes));
+    try!(fs::rename(&tmp, path));
+    Ok(())
+}
+
+/// Writes the saved cache out on a thread of its own, the event loop only
+/// pays for serializing the entries into memory and a slow disk doesn't
+/// hold up answering.
+struct CacheWriter {
+    tx: chan::Sender<(usize, Vec<u8>)>,
+    thread: thread::JoinHandle<()>
+}
+
+impl fmt::Debug for CacheWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CacheWriter")
+    }
+}
+
+impl CacheWriter {
+    fn new(path: PathBuf) -> CacheWriter {
+        // unbuffered, a file is only taken while the thread is idle
+        let (tx, rx) = chan::sync::<(usize, Vec<u8>)>(0);
+        let thread = thread::spawn(move || {
+            for (count, bytes) in rx.iter() {
+                match write_cache(&bytes, &path) {
+                    Ok(()) => info!("saved {} cache entries to {}", count, path.display()),
+                    Err(e) => error!("error saving cache to {}: {}", path.display(), e)
+                }
+            }
+        });
+
+        CacheWriter { tx: tx, thread: thread }
+    }
+
+    /// Hands the file over, unless the last one is still being written.
+    fn write(&self, count: usize, bytes: Vec<u8>) -> bool {
+        let tx = &self.tx;
+        let mut taken = false;
+        chan_select! {
+            default => {},
+            tx.send((count, bytes)) => taken = true
+        }
+        taken
+    }
+
+    /// Writes the last file and waits for it to be on disk.
+    fn finish(self, count: usize, bytes: Vec<u8>) {
+        self.tx.send((count, bytes));
+        drop(self.tx);
+        if self.thread.join().is_err() {
+            error!("cache writer thread panicked");
+        }
+    }
+}
+
+fn load_cache(cache: &mut Cache, path: &Path) -> Result<usize, Error> {
+    let mut bytes = Vec::new();
+    try!(try!(File::open(path)).read_to_end(&mut bytes));
+    cache.load(&bytes)
+}
+
+/// A socket clients reach us on, bound in `main` before privileges are
+/// dropped.
+#[derive (Debug)]
+pub enum Listener {
+    Udp(UdpSocket),
+    Tcp(TcpListener)
+}
+
+impl Listener {
+    /// Binds `spec`, an address prefixed with `udp:` or `tcp:` to serve
+    /// only one of them, a bare address serves both.
+    pub fn bind(spec: &str) -> Result<Vec<Listener>, Error> {
+        let (udp, tcp, addr) = try!(parse_listen(spec));
+
+        let mut listeners = Vec::new();
+        if udp {
+            listeners.push(Listener::Udp(try!(UdpSocket::bound(&addr))));
+        }
+        if tcp {
+            listeners.push(Listener::Tcp(try!(TcpListener::bind(&addr))));
+        }
+        Ok(listeners)
+    }
+
+    fn register(&self, event_loop: &mut EventLoop<Server>, token: Token) -> io::Result<()> {
+        match *self {
+            Listener::Udp(ref s) => event_loop.register(s, token, EventSet::readable(), PollOpt::level() | PollOpt::edge()),
+            Listener::Tcp(ref s) => event_loop.register(s, token, EventSet::readable(), PollOpt::edge())
+        }
+    }
+}
+
+#[derive (Debug)]
+struct ListenSocket {
+    listener: Listener,
+    /// UDP answers waiting for the socket to take them
+    outgoing: VecDeque<Token>
+}
 
 #[derive (Debug)]
 struct Server {
     cache: Cache,
-    socket: UdpSocket,
+    cache_file: Option<PathBuf>,
+    cache_writer: Option<CacheWriter>,
+    last_evictions: u64,
+    listeners: Vec<ListenSocket>,
     datagrams: Slab<Datagram>,
+    connections: Slab<Connection>,
+    /// connections with requests to start on once the current event is done
+    resumed_connections: VecDeque<Token>,
     upstreams: Vec<SocketAddr>,
+    query_timeout_ms: u64,
+    fallback_ms: u64,
+    tcp_idle_ms: u64,
+    /// which address family upstreams are asked first, the one that
+    /// answered last
+    prefer_ipv6: bool,
     queries: Slab<Query>,
-    outgoing_queries: VecDeque<Token>,
 }
 
 const NUM_CONCURRENT_QUERIES: usize = 256;
-const DATAGRAM_BUF_SIZE: usize = NUM_CONCURRENT_QUERIES*2;
+const NUM_CONNECTIONS: usize = 128;
 
 impl Server {
-    fn new(s: UdpSocket) -> Server {
+    fn new(listeners: Vec<Listener>, config: Config) -> Server {
+        // every query may have a datagram out to each upstream
+        let num_datagrams = config.max_queries * cmp::max(config.upstreams.len(), 1);
+
         Server{
-            cache: Cache::new(),
-            socket: s,
-            datagrams: Slab::new_starting_at(Token(2), DATAGRAM_BUF_SIZE),
-            queries: Slab::new_starting_at(Token(0), NUM_CONCURRENT_QUERIES),
-            upstreams: vec!["8.8.8.8:53".parse().unwrap(), "8.8.4.4:53".parse().unwrap()],
-            outgoing_queries: VecDeque::with_capacity(NUM_CONCURRENT_QUERIES)
+            cache: Cache::with_options(config.cache),
+            cache_writer: config.cache_file.clone().map(CacheWriter::new),
+            cache_file: config.cache_file,
+            last_evictions: 0,
+            listeners: listeners.into_iter().map(|l| ListenSocket {
+                listener: l,
+                outgoing: VecDeque::new()
+            }).collect(),
+            datagrams: Slab::new_starting_at(Token(MAX_LISTENERS), num_datagrams),
+            connections: Slab::new_starting_at(Token(MAX_LISTENERS + num_datagrams), config.max_connections),
+            queries: Slab::new_starting_at(Token(0), config.max_queries),
+            upstreams: config.upstreams,
+            query_timeout_ms: config.query_timeout_ms,
+            fallback_ms: config.fallback_ms,
+            tcp_idle_ms: config.tcp_idle_ms,
+            prefer_ipv6: true,
+            resumed_connections: VecDeque::new()
         }
     }
 
-    fn outgoing_query_event(&mut self) -> Result<Option<Token>, Error> {
-        let qt = try!(self.outgoing_queries.front().ok_or("no outgoing queries!"));
+    fn load_cache(&mut self) {
+        if let Some(ref path) = self.cache_file {
+            match load_cache(&mut self.cache, path) {
+                Ok(count) => info!("loaded {} cache entries from {}", count, path.display()),
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                    info!("no cache file at {}, starting empty", path.display())
+                },
+                Err(e) => error!("error loading cache from {}: {}", path.display(), e)
+            }
+        }
+    }
+
+    /// Serializes the cache for the writer thread, the last save is waited
+    /// for when shutting down.
+    fn save_cache(&mut self, last: bool) {
+        if self.cache_writer.is_none() {
+            return
+        }
+
+        let mut bytes = Vec::new();
+        let count = match self.cache.save(&mut bytes) {
+            Ok(count) => count,
+            Err(e) => {
+                error!("error saving cache: {}", e);
+                return
+            }
+        };
+
+        if last {
+            self.cache_writer.take().unwrap().finish(count, bytes);
+        } else if !self.cache_writer.as_ref().unwrap().write(count, bytes) {
+            warn!("cache file still being written, skipping this save");
+        }
+    }
+
+    fn outgoing_query_event(&mut self, listener: usize) -> Result<Option<Token>, Error> {
+        let ls = &self.listeners[listener];
+        let qt = try!(ls.outgoing.front().ok_or("no outgoing queries!"));
+        let socket = match ls.listener {
+            Listener::Udp(ref s) => s,
+            Listener::Tcp(_) => try!(Err("not a udp listener"))
+        };
         let answer_bytes = self.queries[*qt].question_bytes();
 
-        if let Some(size) =  try!(self.socket.send_to(answer_bytes, try!(self.queries[*qt].get_addr().ok_or("no remote address")))) {
+        if let Some(size) =  try!(socket.send_to(answer_bytes, try!(self.queries[*qt].get_addr().ok_or("no remote address")))) {
             if size == answer_bytes.len() {
                 return Ok(Some(*qt))
             }
@@ -56,7 +325,7 @@ impl Server {
         for i in query.upstream_tokens() {
             self.datagrams[i].set_idle();
             try!(self.datagrams[i].reregister(event_loop));
-            try!(event_loop.deregister(self.datagrams[i].socket()));
+            try!(self.datagrams[i].deregister(event_loop));
             self.datagrams.remove(i);
         }
 
@@ -64,9 +333,413 @@ impl Server {
             event_loop.clear_timeout(timeout);
         }
 
+        if let (Some(timeout), _) = query.take_fallback() {
+            event_loop.clear_timeout(timeout);
+        }
+
+        Ok(())
+    }
+
+    fn query_timeout(&mut self, event_loop: &mut EventLoop<Server>, query_token: Token) {
+        if !self.queries.contains(query_token) {
+            warn!("timeout on dead token: {:?}", query_token);
+            return;
+        }
+
+        info!("[{:?}] has timed out", query_token);
+
+        if let Err(e) = self.destroy_query(event_loop, query_token) {
+            warn!("error in destroy query: {:?}", e);
+        }
+
+        if self.queries[query_token].is_prefetch() {
+            self.queries.remove(query_token);
+            return
+        }
+
+        match self.queries[query_token].answer_stale(&mut self.cache) {
+            Ok(true) => {
+                info!("[{:?}] answered from stale cache", query_token);
+                self.answer_ready(event_loop, query_token);
+                self.reregister_socket(event_loop);
+                return
+            },
+            Ok(false) => (),
+            Err(e) => warn!("[{:?}] error in stale lookup: {:?}", query_token, e)
+        }
+
+        self.remove_query(event_loop, query_token);
+    }
+
+    /// Sends the answer in the query's buffer back to whoever asked.
+    fn answer_ready(&mut self, event_loop: &mut EventLoop<Server>, qt: Token) {
+        match self.queries[qt].client() {
+            Client::Udp(listener, _) => self.listeners[listener.as_usize()].outgoing.push_back(qt),
+            Client::Tcp(conn) => {
+                if self.connections.contains(conn) {
+                    if let Err(e) = self.connections[conn].respond(qt, self.queries[qt].question_bytes()) {
+                        warn!("connection [{:?}] error in respond: {:?}", conn, e);
+                    }
+                    if self.connections[conn].has_request() {
+                        self.resumed_connections.push_back(conn);
+                    }
+                    self.update_connection(event_loop, conn);
+                }
+                self.queries.remove(qt);
+            },
+            Client::Prefetch => {
+                self.queries.remove(qt);
+            }
+        }
+    }
+
+    /// Drops a query that won't be answered.
+    fn remove_query(&mut self, event_loop: &mut EventLoop<Server>, qt: Token) {
+        if let Some(Client::Tcp(conn)) = self.queries.remove(qt).map(|q| q.client()) {
+            if self.connections.contains(conn) {
+                self.connections[conn].forget_query(qt);
+                if self.connections[conn].has_request() {
+                    self.resumed_connections.push_back(conn);
+                }
+                self.update_connection(event_loop, conn);
+            }
+        }
+    }
+
+    /// Looks a new query up in the cache, asks the upstreams on a miss.
+    fn resolve(&mut self, event_loop: &mut EventLoop<Server>, query_tok: Token) {
+        match self.queries[query_tok].answer_bad_version() {
+            Ok(true) => {
+                self.answer_ready(event_loop, query_tok);
+                return
+            },
+            Ok(false) => (),
+            Err(e) => error!("query [{:?}] error in version check: {:?}", query_tok, e)
+        }
+
+        let in_cache = match self.queries[query_tok].answer_from_cache(&mut self.cache) {
+            Ok(b) => b,
+            Err(e) => {
+                error!("query [{:?}] error in cache lookup: {:?}", query_tok, e);
+                false
+            }
+        };
+
+        if in_cache {
+            self.maybe_prefetch(event_loop, query_tok);
+            self.answer_ready(event_loop, query_tok);
+        } else {
+            self.query_upstreams(event_loop, query_tok);
+        }
+    }
+
+    fn accept_connections(&mut self, event_loop: &mut EventLoop<Server>, listener: usize) {
+        loop {
+            let accepted = match self.listeners[listener].listener {
+                Listener::Tcp(ref l) => l.accept(),
+                Listener::Udp(_) => return
+            };
+            let (stream, addr) = match accepted {
+                Ok(Some(s)) => s,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("tcp accept error: {:?}", e);
+                    return
+                }
+            };
+
+            let token = match self.connections.insert_with(|t| Connection::new(t, stream, addr)) {
+                Some(t) => t,
+                None => {
+                    warn!("too many connections, dropping {}", addr);
+                    continue
+                }
+            };
+
+            debug!("connection [{:?}] from {}", token, addr);
+            if let Err(e) = self.connections[token].register(event_loop) {
+                error!("connection [{:?}] error in register: {:?}", token, e);
+                self.connections.remove(token);
+                continue
+            }
+            self.reset_idle_timeout(event_loop, token);
+        }
+    }
+
+    fn connection_event(&mut self, event_loop: &mut EventLoop<Server>, token: Token, events: EventSet) -> Result<(), Error> {
+        if events.is_error() {
+            try!(Err("socket error"));
+        }
+
+        if events.is_writable() {
+            try!(self.connections[token].write());
+        }
+
+        if events.is_readable() || events.is_hup() {
+            try!(self.connections[token].read());
+            try!(self.connection_requests(event_loop, token));
+        }
+
+        if self.connections.contains(token) {
+            self.reset_idle_timeout(event_loop, token);
+        }
+        Ok(())
+    }
+
+    /// Starts on the requests read from a connection, as many as it may
+    /// have pending.
+    fn connection_requests(&mut self, event_loop: &mut EventLoop<Server>, token: Token) -> Result<(), Error> {
+        while let Some(request) = try!(self.connections[token].next_request()) {
+            let query_tok = match self.queries.insert_with(|qt| Query::new(qt)) {
+                Some(t) => t,
+                None => {
+                    warn!("connection [{:?}] too many queries, answering SERVFAIL", token);
+                    try!(self.refuse_request(token, &request, Rcode::ServFail));
+                    continue
+                }
+            };
+
+            if let Err(e) = self.queries[query_tok].tcp_request(token, &request) {
+                warn!("connection [{:?}] bad request: {:?}", token, e);
+                self.queries.remove(query_tok);
+                try!(self.refuse_request(token, &request, Rcode::FormErr));
+                continue
+            }
+            self.connections[token].add_query(query_tok);
+            self.resolve(event_loop, query_tok);
+
+            // answering from the cache may have closed it already
+            if !self.connections.contains(token) {
+                break
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a request read from a connection with just `rcode`, unless
+    /// there's no header to answer or it is an answer itself.
+    fn refuse_request(&mut self, token: Token, request: &[u8], rcode: Rcode) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        match MessageRef::new(request) {
+            Ok(ref m) if !m.is_response() => try!(m.write_error(rcode, &mut bytes)),
+            _ => return Ok(())
+        }
+        try!(self.connections[token].reply(&bytes));
         Ok(())
     }
 
+    /// Starts on the requests held back while connections had as many
+    /// queries pending as they may.
+    fn resume_connections(&mut self, event_loop: &mut EventLoop<Server>) {
+        while let Some(token) = self.resumed_connections.pop_front() {
+            if !self.connections.contains(token) {
+                continue
+            }
+
+            match self.connection_requests(event_loop, token) {
+                Ok(()) => if self.connections.contains(token) {
+                    self.update_connection(event_loop, token)
+                },
+                Err(e) => {
+                    debug!("connection [{:?}] error: {:?}", token, e);
+                    self.close_connection(event_loop, token);
+                }
+            }
+        }
+    }
+
+    /// Brings the connection's registration up to date with what it waits
+    /// on, closes it once the client is gone and everything is answered.
+    fn update_connection(&mut self, event_loop: &mut EventLoop<Server>, token: Token) {
+        if self.connections[token].is_finished() {
+            self.close_connection(event_loop, token);
+            return
+        }
+
+        if let Err(e) = self.connections[token].reregister(event_loop) {
+            error!("connection [{:?}] error in reregister: {:?}", token, e);
+            self.close_connection(event_loop, token);
+        }
+    }
+
+    fn reset_idle_timeout(&mut self, event_loop: &mut EventLoop<Server>, token: Token) {
+        if let Some(timeout) = self.connections[token].take_timeout() {
+            event_loop.clear_timeout(timeout);
+        }
+
+        match event_loop.timeout_ms(Timer::Connection(token), self.tcp_idle_ms) {
+            Ok(timeout) => self.connections[token].set_timeout(timeout),
+            Err(e) => error!("connection [{:?}] error in scheduling idle timeout: {:?}", token, e)
+        }
+    }
+
+    fn connection_timeout(&mut self, event_loop: &mut EventLoop<Server>, token: Token) {
+        if !self.connections.contains(token) {
+            warn!("timeout on dead connection: {:?}", token);
+            return;
+        }
+
+        // the connection isn't idle while its queries are being answered
+        if self.connections[token].is_idle() {
+            debug!("connection [{:?}] idle, closing", token);
+            self.close_connection(event_loop, token);
+        } else {
+            self.reset_idle_timeout(event_loop, token);
+        }
+    }
+
+    /// Closes a connection, giving up on any queries still pending on it.
+    fn close_connection(&mut self, event_loop: &mut EventLoop<Server>, token: Token) {
+        let mut connection = match self.connections.remove(token) {
+            Some(c) => c,
+            None => return
+        };
+
+        for qt in connection.queries().iter().cloned() {
+            if let Err(e) = self.destroy_query(event_loop, qt) {
+                warn!("error in destroy query: {:?}", e);
+            }
+            self.queries.remove(qt);
+        }
+
+        if let Some(timeout) = connection.take_timeout() {
+            event_loop.clear_timeout(timeout);
+        }
+        if let Err(e) = event_loop.deregister(connection.socket()) {
+            warn!("connection [{:?}] error in deregister: {:?}", token, e);
+        }
+        debug!("connection [{:?}] from {} closed", token, connection.get_addr());
+    }
+
+    fn reregister_socket(&mut self, event_loop: &mut EventLoop<Server>) {
+        for (i, ls) in self.listeners.iter().enumerate() {
+            let socket = match ls.listener {
+                Listener::Udp(ref s) => s,
+                Listener::Tcp(_) => continue
+            };
+
+            if ls.outgoing.is_empty() {
+                if let Err(e) = event_loop.reregister(socket, Token(i), EventSet::readable(), PollOpt::level() | PollOpt::edge()) {
+                   error!("listening socket rx reregister failed: {:?}", e); 
+                }
+            } else {
+                if let Err(e) = event_loop.reregister(socket, Token(i), EventSet::readable() | EventSet::writable(), PollOpt::level() | PollOpt::edge()) {
+                   error!("listening socket tx/rx reregister failed: {:?}", e); 
+                }
+            }
+        }
+    }
+
+    /// Sends the query's question to every upstream.
+    fn query_upstreams(&mut self, event_loop: &mut EventLoop<Server>, query_tok: Token) {
+        let prefer_ipv6 = self.prefer_ipv6;
+        let (mut first, mut later): (Vec<SocketAddr>, Vec<SocketAddr>) =
+            self.upstreams.iter().partition(|a| is_ipv6(a) == prefer_ipv6);
+
+        // none of the preferred family are configured
+        if first.is_empty() {
+            first = later;
+            later = Vec::new();
+        }
+
+        self.send_to_upstreams(event_loop, query_tok, &first);
+
+        // the other family gets asked too if the first is slow to answer
+        if !later.is_empty() {
+            match event_loop.timeout_ms(Timer::Fallback(query_tok), self.fallback_ms) {
+                Ok(timeout) => self.queries[query_tok].set_fallback(timeout, later),
+                Err(e) => {
+                    error!("error in scheduling fallback: {:?}", e);
+                    self.send_to_upstreams(event_loop, query_tok, &later);
+                }
+            }
+        }
+
+        let timeout = event_loop.timeout_ms(Timer::Query(query_tok), self.query_timeout_ms).unwrap();
+
+        self.queries[query_tok].set_timeout(timeout);
+    }
+
+    /// Asks the upstreams held back for a query right away.
+    fn fall_back(&mut self, event_loop: &mut EventLoop<Server>, query_tok: Token) {
+        if !self.queries.contains(query_tok) {
+            return
+        }
+
+        let (timeout, upstreams) = self.queries[query_tok].take_fallback();
+        if let Some(timeout) = timeout {
+            event_loop.clear_timeout(timeout);
+        }
+
+        if !upstreams.is_empty() {
+            debug!("[{:?}] falling back to {} more upstreams", query_tok, upstreams.len());
+            self.send_to_upstreams(event_loop, query_tok, &upstreams);
+        }
+    }
+
+    fn send_to_upstreams(&mut self, event_loop: &mut EventLoop<Server>, query_tok: Token, upstreams: &[SocketAddr]) {
+        let query = &mut self.queries[query_tok];
+        for upstream in upstreams.iter() {
+            let socket = match socket_for(upstream) {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("no socket for upstream {}: {:?}", upstream, e);
+                    continue;
+                }
+            };
+            // get a datagram for outgoing
+            let token = match self.datagrams.insert_with(|token| Datagram::new(token, query_tok, upstream.clone(), socket)) {
+                Some(t) => t,
+                None => {
+                    error!("error in datagram insert");
+                    //self.queries.remove(query_tok);
+                    return;
+                }
+            };
+            // link the query to the token
+            query.add_upstream_token(token);
+            // give it the correct bytes FIXME: a copy
+            if let Err(e) = self.datagrams[token].fill(query.question_bytes()) {
+                error!("datagram [{:?}] error in fill: {:?}", token, e);
+                return;
+            }
+            // register this datagram with the write event
+            if let Err(e) = self.datagrams[token].register(event_loop) {
+                error!("datagram [{:?}] error in register: {:?}", token, e);
+            }
+        }
+    }
+
+    /// Refreshes the entry a cached answer came from if it is popular and
+    /// about to expire, the refresh runs as a query of its own that nobody
+    /// waits on.
+    fn maybe_prefetch(&mut self, event_loop: &mut EventLoop<Server>, query_tok: Token) {
+        let message = match self.queries[query_tok].cache_key() {
+            Some(ref key) if self.cache.prefetch_due(key) => match self.queries[query_tok].message() {
+                Some(m) => m.clone(),
+                None => return
+            },
+            _ => return
+        };
+
+        let prefetch_tok = match self.queries.insert_with(|qt| Query::new(qt)) {
+            Some(t) => t,
+            None => {
+                warn!("no room for prefetch query");
+                return;
+            }
+        };
+
+        if let Err(e) = self.queries[prefetch_tok].prefetch(&message) {
+            error!("prefetch [{:?}] error: {:?}", prefetch_tok, e);
+            self.queries.remove(prefetch_tok);
+            return;
+        }
+
+        debug!("[{:?}] prefetching for [{:?}]", prefetch_tok, query_tok);
+        self.query_upstreams(event_loop, prefetch_tok);
+    }
+
     fn datagram_event(&mut self, token: Token, event_loop: &mut EventLoop<Server>, events: EventSet) -> Result<(), Error> {
         if !self.datagrams.contains(token) {
             // event in queue for a dead token
@@ -83,8 +756,47 @@ impl Server {
         let done = try!(self.queries[qt].datagram_event(&mut self.datagrams[token], events));
 
         if done {
-            self.outgoing_queries.push_back(self.datagrams[token].query_token());
-            return self.destroy_query(event_loop, qt)
+            let ipv6 = is_ipv6(self.datagrams[token].get_addr());
+            if ipv6 != self.prefer_ipv6 {
+                info!("preferring ipv{} upstreams", if ipv6 { 6 } else { 4 });
+                self.prefer_ipv6 = ipv6;
+            }
+
+            // the answer went to the client without being decoded, here it
+            // is decoded in full once to go into the cache, which has to own
+            // its records, and the return code is read off that. Answers to
+            // opaque requests stay out of the cache.
+            let cacheable = self.queries[qt].message().is_some();
+            let servfail = match MessageRef::new(self.datagrams[token].get_ref()) {
+                Ok(answer) if cacheable => match answer.to_message() {
+                    Ok(m) => {
+                        self.cache.insert_message(&m);
+                        m.rcode() == Rcode::ServFail
+                    },
+                    Err(e) => {
+                        warn!("[{:?}] error decoding answer for the cache: {:?}", qt, e);
+                        answer.rcode() == Rcode::ServFail
+                    }
+                },
+                Ok(answer) => answer.rcode() == Rcode::ServFail,
+                Err(_) => false
+            };
+
+            if self.queries[qt].is_prefetch() {
+                // nobody to answer, the cache has been refreshed
+                try!(self.destroy_query(event_loop, qt));
+                self.queries.remove(qt);
+                return Ok(())
+            }
+
+            // rather hand out stale data than a SERVFAIL
+            if servfail {
+                if let Err(e) = self.queries[qt].answer_stale(&mut self.cache) {
+                    warn!("[{:?}] error in stale lookup: {:?}", qt, e);
+                }
+            }
+            try!(self.destroy_query(event_loop, qt));
+            self.answer_ready(event_loop, qt);
         } else {
             try!(self.datagrams[token].reregister(event_loop))
         }
@@ -92,37 +804,98 @@ impl Server {
     }
 }
 
-#[derive (Debug)]
 pub enum ServerEvent {
-    Quit
+    Quit,
+    /// Lists the cache entries at or below a name.
+    ListCache(String, chan::Sender<Vec<EntryInfo>>),
+    /// Flushes every type cached for a name.
+    FlushName(String, chan::Sender<usize>),
+    /// Flushes a name and everything below it.
+    FlushTree(String, chan::Sender<usize>),
+    FlushAll(chan::Sender<usize>),
+    /// Reports the cache's counters, how many entries it holds and about
+    /// how much memory they take.
+    CacheStats(chan::Sender<(Stats, usize, usize)>)
+}
+
+impl fmt::Debug for ServerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerEvent::Quit => write!(f, "Quit"),
+            ServerEvent::ListCache(ref name, _) => write!(f, "ListCache({})", name),
+            ServerEvent::FlushName(ref name, _) => write!(f, "FlushName({})", name),
+            ServerEvent::FlushTree(ref name, _) => write!(f, "FlushTree({})", name),
+            ServerEvent::FlushAll(_) => write!(f, "FlushAll"),
+            ServerEvent::CacheStats(_) => write!(f, "CacheStats")
+        }
+    }
 }
 
 impl Handler for Server {
-    type Timeout = Token;
+    type Timeout = Timer;
     type Message = ServerEvent;
 
     fn notify(&mut self, event_loop: &mut EventLoop<Server>, msg: ServerEvent) {
         match msg {
             ServerEvent::Quit => {
                 info!("Received quit event, shutting down event loop.");
+                self.save_cache(true);
                 event_loop.shutdown();
+            },
+            ServerEvent::ListCache(name, reply) => {
+                reply.send(self.cache.list(&name));
+            },
+            ServerEvent::FlushName(name, reply) => {
+                info!("flushing {} from the cache", name);
+                reply.send(self.cache.flush(&name));
+            },
+            ServerEvent::FlushTree(name, reply) => {
+                info!("flushing {} and below from the cache", name);
+                reply.send(self.cache.flush_tree(&name));
+            },
+            ServerEvent::FlushAll(reply) => {
+                info!("flushing the cache");
+                reply.send(self.cache.flush_all());
+            },
+            ServerEvent::CacheStats(reply) => {
+                reply.send((*self.cache.stats(), self.cache.len(), self.cache.bytes()));
             }
         }
     }
 
-    fn timeout(&mut self, event_loop: &mut EventLoop<Server>, query_token: Token) {
-        if !self.queries.contains(query_token) {
-            warn!("timeout on dead token: {:?}", query_token);
-            return;
-        }
+    fn timeout(&mut self, event_loop: &mut EventLoop<Server>, timer: Timer) {
+        match timer {
+            Timer::Query(query_token) => {
+                self.query_timeout(event_loop, query_token);
+                self.resume_connections(event_loop);
+            },
+            Timer::Fallback(query_token) => self.fall_back(event_loop, query_token),
+            Timer::Connection(token) => self.connection_timeout(event_loop, token),
+            Timer::CacheSweep => {
+                let evictions = self.cache.stats().evictions;
+                let removed = self.cache.sweep();
 
-        info!("[{:?}] has timed out", query_token);
+                debug!("cache sweep removed {} entries, {} left using {} bytes: {:?}",
+                       removed, self.cache.len(), self.cache.bytes(), self.cache.stats());
 
-        if let Err(e) = self.destroy_query(event_loop, query_token) {
-            warn!("error in destroy query: {:?}", e);
-        }
+                if evictions != self.last_evictions {
+                    info!("cache is full, {} entries evicted since the last sweep",
+                          evictions - self.last_evictions);
+                    self.last_evictions = evictions;
+                }
 
-        self.queries.remove(query_token);
+                if let Err(e) = event_loop.timeout_ms(Timer::CacheSweep, CACHE_SWEEP_MS) {
+                    error!("error in scheduling cache sweep: {:?}", e);
+                }
+            },
+            Timer::CacheSave => {
+                self.save_cache(false);
+
+                if let Err(e) = event_loop.timeout_ms(Timer::CacheSave, CACHE_SAVE_MS) {
+                    error!("error in scheduling cache save: {:?}", e);
+                }
+            }
+        }
     }
 
     fn interrupted(&mut self, event_loop: &mut EventLoop<Server>) {
@@ -131,7 +904,14 @@ impl Handler for Server {
     }
 
     fn ready(&mut self, event_loop: &mut EventLoop<Server>, token: Token, events: EventSet) {
-        if token == SERVER {
+        let is_udp = match self.listeners.get(token.as_usize()) {
+            Some(&ListenSocket { listener: Listener::Udp(_), .. }) => Some(true),
+            Some(&ListenSocket { listener: Listener::Tcp(_), .. }) => Some(false),
+            None => None
+        };
+
+        if is_udp == Some(true) {
+            let listener = token.as_usize();
 
             if events.is_readable() {
                 let query_tok = match self.queries.insert_with(|qt| Query::new(qt)) {
@@ -142,39 +922,13 @@ impl Handler for Server {
                     }
                 };
 
-                match self.queries[query_tok].rx(&self.socket) {
-                    Ok(Some(())) => {
-                        if self.queries[query_tok].answer_in_cache(&self.cache) {
-                        } else {
-                            let query = &mut self.queries[query_tok];
-                            for upstream in self.upstreams.iter() {
-                                // get a datagram for outgoing
-                                let token = match self.datagrams.insert_with(|token| Datagram::new(token, query_tok, upstream.clone())) {
-                                    Some(t) => t,
-                                    None => {
-                                        error!("error in datagram insert");
-                                        //self.queries.remove(query_tok);
-                                        return;
-                                    }
-                                };
-                                // link the query to the token
-                                query.add_upstream_token(token);
-                                // give it the correct bytes FIXME: a copy
-                                if let Err(e) = self.datagrams[token].fill(query.question_bytes()) {
-                                    error!("datagram [{:?}] error in fill: {:?}", token, e);
-                                    return;
-                                }
-                                // register this datagram with the write event
-                                if let Err(e) = self.datagrams[token].register(event_loop) {
-                                    error!("datagram [{:?}] error in register: {:?}", token, e);
-                                }
-                            }
-
-                            let timeout = event_loop.timeout_ms(query_tok, 10 * 1000).unwrap();
-
-                            query.set_timeout(timeout);
-                        }
-                    },
+                let rx = match self.listeners[listener].listener {
+                    Listener::Udp(ref s) => self.queries[query_tok].rx(token, s),
+                    Listener::Tcp(_) => unreachable!()
+                };
+
+                match rx {
+                    Ok(Some(())) => self.resolve(event_loop, query_tok),
                     Ok(None) => {
                         self.queries.remove(query_tok);
                         error!("no data?");
@@ -188,9 +942,9 @@ impl Handler for Server {
                 }
             }
             if events.is_writable() {
-                match self.outgoing_query_event() {
+                match self.outgoing_query_event(listener) {
                     Ok(Some(query_token)) => {
-                        let qt = self.outgoing_queries.pop_front().expect("this shouldn't happen");
+                        let qt = self.listeners[listener].outgoing.pop_front().expect("this shouldn't happen");
                         assert!(qt == query_token);
                         self.queries.remove(qt);
                     },
@@ -200,31 +954,49 @@ impl Handler for Server {
                     }
                 }
             }
+        } else if is_udp == Some(false) {
+            self.accept_connections(event_loop, token.as_usize());
+        } else if self.connections.contains(token) {
+            match self.connection_event(event_loop, token, events) {
+                Ok(()) => if self.connections.contains(token) {
+                    self.update_connection(event_loop, token)
+                },
+                Err(e) => {
+                    debug!("connection [{:?}] error: {:?}", token, e);
+                    self.close_connection(event_loop, token);
+                }
+            }
         } else {
             // these are a query's datagram tx/r
             if let Err(e) = self.datagram_event(token, event_loop, events) {
                 error!("datagram event caught error: {:?}", e);
                 //self.datagrams.remove(token);
-            }
-        }
 
-        if self.outgoing_queries.is_empty() {
-            if let Err(e) = event_loop.reregister(&self.socket, SERVER, EventSet::readable(), PollOpt::level() | PollOpt::edge()) {
-               error!("listening socket rx reregister failed: {:?}", e); 
-            }
-        } else {
-            if let Err(e) = event_loop.reregister(&self.socket, SERVER, EventSet::readable() | EventSet::writable(), PollOpt::level() | PollOpt::edge()) {
-               error!("listening socket tx/rx reregister failed: {:?}", e); 
+                // no use waiting on the other family if this one is unreachable
+                if let Some(qt) = self.datagrams.get(token).map(|d| d.query_token()) {
+                    self.fall_back(event_loop, qt);
+                }
             }
         }
+
+        self.resume_connections(event_loop);
+        self.reregister_socket(event_loop);
     }
 }
 
-pub fn run_server(s: UdpSocket) -> (thread::JoinHandle<()>, mio::Sender<ServerEvent>, chan::Receiver<i32>) {
+pub fn run_server(listeners: Vec<Listener>, config: Config) -> (thread::JoinHandle<()>, mio::Sender<ServerEvent>, chan::Receiver<i32>) {
     let mut evt_loop = EventLoop::new().ok().expect("event loop failed");
 
-    evt_loop.register(&s, SERVER, EventSet::readable(), PollOpt::level()| PollOpt::edge())
-        .ok().expect("registration failed");
+    assert!(listeners.len() <= MAX_LISTENERS, "too many listeners");
+    for (i, l) in listeners.iter().enumerate() {
+        l.register(&mut evt_loop, Token(i)).ok().expect("registration failed");
+    }
+
+    evt_loop.timeout_ms(Timer::CacheSweep, CACHE_SWEEP_MS).ok().expect("cache sweep timer failed");
+
+    if config.cache_file.is_some() {
+        evt_loop.timeout_ms(Timer::CacheSave, CACHE_SAVE_MS).ok().expect("cache save timer failed");
+    }
 
     let (end_sender, rx) = chan::sync(0);
 
@@ -232,7 +1004,9 @@ pub fn run_server(s: UdpSocket) -> (thread::JoinHandle<()>, mio::Sender<ServerEv
 
     let thr = thread::spawn(move || {
         info!("EventLoop thread started!");
-        evt_loop.run(&mut Server::new(s)).ok().expect("event loop run");
+        let mut server = Server::new(listeners, config);
+        server.load_cache();
+        evt_loop.run(&mut server).ok().expect("event loop run");
         info!("EventLoop thread ended!");
         chan_select! {
             default => {},
@@ -248,49 +1022,50 @@ mod tests {
     use self::super::*;
     use dns::*;
     use std::net::*;
-    use mio;
     use std::thread;
 
-    fn test_dns_request(b: &[u8], dest: &SocketAddr) -> [u8;512] {
-        let local: SocketAddr = "0.0.0.0:0".parse().unwrap();
-        let socket = UdpSocket::bind(&local).unwrap();
-
-        let size = socket.send_to(b, &dest).unwrap();
-
-        let mut bytes = [0u8; 512];
-
-        let (bytes_written, recv_socket) = socket.recv_from(&mut bytes).unwrap();
+    /// Binds listeners to `spec` on a port of the system's choosing, along
+    /// with the address the first one ended up on.
+    fn bind_any_port(spec: &str) -> (Vec<Listener>, SocketAddr) {
+        let listeners = Listener::bind(spec).unwrap();
+        let addr = match listeners[0] {
+            Listener::Udp(ref s) => s.local_addr().unwrap(),
+            Listener::Tcp(ref l) => l.local_addr().unwrap()
+        };
+        (listeners, addr)
+    }
 
-        bytes
+    /// Whether IPv6 loopback is there to test with, some sandboxes lack it.
+    fn have_ipv6() -> bool {
+        ::std::net::UdpSocket::bind("[::1]:0").is_ok()
     }
 
     #[test]
     fn simple_proxy() {
         let request = include_bytes!("../test/dns_request.bin");
+        let msg = Message::new(include_bytes!("../test/dns_response.bin")).unwrap();
 
-        let google_dns = "8.8.8.8:53".parse().unwrap();
-
-        let response = test_dns_request(request, &google_dns);
-
-        let msg = Message::new(&response).unwrap();
-
-        let _ = thread::spawn(move || {
-            let server_addr = "0.0.0.0:9080".parse().unwrap();
-            let s = mio::udp::UdpSocket::bound(&server_addr).unwrap();
-            run_server(s);
+        // an upstream that answers with the capture
+        let upstream = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let answerer = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (_, from) = upstream.recv_from(&mut buf).unwrap();
+            upstream.send_to(include_bytes!("../test/dns_response.bin"), &from).unwrap();
         });
 
+        let (listeners, server_addr) = bind_any_port("udp:127.0.0.1:0");
+        let (thr, sender, _) = run_server(listeners, Config { upstreams: vec![upstream_addr], .. Config::default() });
 
-        let t = thread::spawn(move || {
-            let addr: SocketAddr = "0.0.0.0:9080".parse().unwrap();
-            let request = include_bytes!("../test/dns_request.bin");
-            let output = test_dns_request(request, &addr);
-            let msg = Message::new(&output).unwrap();
-
-            msg
-        });
+        let client = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(request, &server_addr).unwrap();
+        let mut buf = [0u8; 512];
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        let res = Message::new(&buf[..size]).unwrap();
 
-        let res = t.join().unwrap();
+        answerer.join().unwrap();
+        sender.send(ServerEvent::Quit).unwrap();
+        thr.join().unwrap();
 
         assert_eq!(msg.tx_id, res.tx_id);
         assert_eq!(msg.answers.len(), res.answers.len());
@@ -298,4 +1073,274 @@ mod tests {
         assert_eq!(msg.answers[0].r_type, res.answers[0].r_type);
         assert_eq!(msg.answers[0].r_class, res.answers[0].r_class);
     }
+
+    #[test]
+    fn tcp_pipelining() {
+        use std::io::{Read, Write};
+        use std::env;
+        use std::process;
+        use cache::Cache;
+
+        // answers come out of the cache, no upstream is needed
+        let response = Message::new(include_bytes!("../test/dns_response.bin")).unwrap();
+        let mut cache = Cache::new();
+        cache.insert_message(&response);
+        let cache_file = env::temp_dir().join(format!("pdnsd-tcp-pipelining-{}.cache", process::id()));
+        save_cache(&cache, &cache_file).unwrap();
+
+        let (listeners, server_addr) = bind_any_port("tcp:127.0.0.1:0");
+        let (thr, sender, _) = run_server(listeners, Config { cache_file: Some(cache_file.clone()), .. Config::default() });
+
+        let mut request = include_bytes!("../test/dns_request.bin").to_vec();
+        let mut stream = TcpStream::connect(&server_addr).unwrap();
+        let mut frames = Vec::new();
+        for tx_id in 1..4u8 {
+            request[0] = 0;
+            request[1] = tx_id;
+            frames.extend_from_slice(&[0, request.len() as u8]);
+            frames.extend_from_slice(&request);
+        }
+        stream.write_all(&frames).unwrap();
+
+        let mut tx_ids = Vec::new();
+        for _ in 0..3 {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut answer = vec![0u8; len[0] as usize * 256 + len[1] as usize];
+            stream.read_exact(&mut answer).unwrap();
+
+            let msg = Message::new(&answer).unwrap();
+            assert_eq!(msg.answers.len(), response.answers.len());
+            tx_ids.push(msg.tx_id);
+        }
+        tx_ids.sort();
+        assert_eq!(tx_ids, vec![1, 2, 3]);
+
+        // the server closes the connection once we're done with it
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(stream.read(&mut [0u8; 2]).unwrap(), 0);
+
+        sender.send(ServerEvent::Quit).unwrap();
+        thr.join().unwrap();
+        fs::remove_file(&cache_file).unwrap();
+    }
+
+    #[test]
+    fn truncated_answer_retried_over_tcp() {
+        use std::io::{Read, Write};
+        use std::net::TcpListener;
+
+        // more than fits in a UDP buffer of ours
+        let mut full = Message::new(include_bytes!("../test/multi_a_response.bin")).unwrap();
+        let record = full.answers[0].clone();
+        for i in 0..300 {
+            let mut r = record.clone();
+            r.r_data = ResourceData::A(Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8));
+            full.answers.push(r);
+        }
+
+        // an upstream that only gives the whole answer over TCP
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = tcp.local_addr().unwrap();
+        let udp = ::std::net::UdpSocket::bind(&upstream_addr).unwrap();
+        let answers = full.answers.clone();
+        let upstream = thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (size, from) = udp.recv_from(&mut buf).unwrap();
+            let query = Message::new(&buf[..size]).unwrap();
+            let mut truncated = query.response(&[]);
+            truncated.set_truncated(true);
+            udp.send_to(&truncated.to_bytes().unwrap(), &from).unwrap();
+
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut request = vec![0u8; len[0] as usize * 256 + len[1] as usize];
+            stream.read_exact(&mut request).unwrap();
+            let answer = Message::new(&request).unwrap().response(&answers).to_bytes().unwrap();
+            stream.write_all(&[(answer.len() >> 8) as u8, answer.len() as u8]).unwrap();
+            stream.write_all(&answer).unwrap();
+        });
+
+        let (listeners, server_addr) = bind_any_port("udp:127.0.0.1:0");
+        let (thr, sender, _) = run_server(listeners, Config { upstreams: vec![upstream_addr], .. Config::default() });
+
+        // the client takes all of it
+        let mut request = Message::new(include_bytes!("../test/multi_a_request.bin")).unwrap();
+        request.edns = Some(Edns::new(0xffff));
+        let client = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&request.to_bytes().unwrap(), &server_addr).unwrap();
+        let mut buf = vec![0u8; 0x10000];
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        assert!(size > 4096);
+        let msg = Message::new(&buf[..size]).unwrap();
+
+        assert!(!msg.is_truncated());
+        assert_eq!(msg.answers.len(), full.answers.len());
+
+        upstream.join().unwrap();
+        sender.send(ServerEvent::Quit).unwrap();
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn unknown_edns_version() {
+        let (listeners, server_addr) = bind_any_port("udp:127.0.0.1:0");
+        let (thr, sender, _) = run_server(listeners, Config::default());
+
+        let mut request = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        request.edns = Some(Edns { version: 1, .. Edns::new(4096) });
+        let client = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&request.to_bytes().unwrap(), &server_addr).unwrap();
+
+        let mut buf = [0u8; 512];
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        let msg = Message::new(&buf[..size]).unwrap();
+        assert_eq!(msg.tx_id, request.tx_id);
+        assert_eq!(msg.rcode(), Rcode::BadVers);
+        assert_eq!(msg.edns.unwrap().version, 0);
+
+        sender.send(ServerEvent::Quit).unwrap();
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn dotted_label_passed_through() {
+        // a DNS-SD SRV query, "Printer.2" is one label
+        let mut request = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        request.extend_from_slice(b"\x09Printer.2\x04_ipp\x04_tcp\x05local\x00\x00\x21\x00\x01");
+        assert!(Message::new(&request).is_err());
+
+        let upstream = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let (listeners, server_addr) = bind_any_port("udp:127.0.0.1:0");
+        let (thr, sender, _) = run_server(listeners, Config { upstreams: vec![upstream_addr], .. Config::default() });
+
+        let client = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&request, &server_addr).unwrap();
+
+        // it goes upstream as it came in, and so does the answer
+        let mut buf = [0u8; 512];
+        let (size, from) = upstream.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &request[..]);
+        let mut answer = request.clone();
+        answer[2] |= 0x80;
+        answer[3] = 0x83;
+        upstream.send_to(&answer, &from).unwrap();
+
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &answer[..]);
+
+        sender.send(ServerEvent::Quit).unwrap();
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn requests_not_taken_on() {
+        use std::io::{Read, Write};
+
+        // never answers, the first query holds the only slot
+        let upstream = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (listeners, server_addr) = bind_any_port("tcp:127.0.0.1:0");
+        let config = Config { upstreams: vec![upstream.local_addr().unwrap()], max_queries: 1, .. Config::default() };
+        let (thr, sender, _) = run_server(listeners, config);
+
+        // a header followed by a name that can't be read
+        let mut frames = vec![0, 14, 0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 0xff];
+        let mut request = include_bytes!("../test/dns_request.bin").to_vec();
+        for tx_id in 2..4u8 {
+            request[0] = 0;
+            request[1] = tx_id;
+            frames.extend_from_slice(&[0, request.len() as u8]);
+            frames.extend_from_slice(&request);
+        }
+
+        let mut stream = TcpStream::connect(&server_addr).unwrap();
+        stream.write_all(&frames).unwrap();
+
+        let mut answers = Vec::new();
+        for _ in 0..2 {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut answer = vec![0u8; len[0] as usize * 256 + len[1] as usize];
+            stream.read_exact(&mut answer).unwrap();
+            let msg = Message::new(&answer).unwrap();
+            answers.push((msg.tx_id, msg.rcode()));
+        }
+        assert_eq!(answers, vec![(1, Rcode::FormErr), (3, Rcode::ServFail)]);
+
+        sender.send(ServerEvent::Quit).unwrap();
+        thr.join().unwrap();
+    }
+
+    #[test]
+    fn listener_specs() {
+        let (both, addr) = bind_any_port("127.0.0.1:0");
+        assert_eq!(both.len(), 2);
+        // taken already
+        assert!(Listener::bind(&addr.to_string()).is_err());
+        assert!(Listener::bind("udp:localhost").is_err());
+
+        if !have_ipv6() {
+            return
+        }
+
+        let (udp, addr) = bind_any_port("udp:[::1]:0");
+        assert_eq!(udp.len(), 1);
+        match udp[0] {
+            Listener::Udp(_) => assert!(addr.ip().is_loopback() && is_ipv6(&addr)),
+            _ => panic!("not a udp listener")
+        }
+
+        let tcp = Listener::bind("tcp:[::1]:0").unwrap();
+        assert_eq!(tcp.len(), 1);
+        match tcp[0] {
+            Listener::Tcp(_) => (),
+            _ => panic!("not a tcp listener")
+        }
+    }
+
+    #[test]
+    fn upstream_addresses() {
+        assert_eq!(parse_upstream("8.8.8.8").unwrap(), "8.8.8.8:53".parse().unwrap());
+        assert_eq!(parse_upstream("127.0.0.1:5353").unwrap(), "127.0.0.1:5353".parse().unwrap());
+        assert_eq!(parse_upstream("2001:4860:4860::8888").unwrap(), "[2001:4860:4860::8888]:53".parse().unwrap());
+        assert_eq!(parse_upstream("[2001:4860:4860::8888]:53").unwrap(), "[2001:4860:4860::8888]:53".parse().unwrap());
+        assert!(parse_upstream("dns.google").is_err());
+        assert!(parse_upstream("[::1]").is_err());
+    }
+
+    #[test]
+    fn ipv4_fallback_for_silent_ipv6_upstream() {
+        if !have_ipv6() {
+            return
+        }
+
+        let v6 = ::std::net::UdpSocket::bind("[::1]:0").unwrap();
+        let v4 = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let v6_addr = v6.local_addr().unwrap();
+        let v4_addr = v4.local_addr().unwrap();
+
+        let (listeners, server_addr) = bind_any_port("udp:127.0.0.1:0");
+        let (thr, sender, _) = run_server(listeners, Config { upstreams: vec![v4_addr, v6_addr], .. Config::default() });
+
+        let client = ::std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(include_bytes!("../test/dns_request.bin"), &server_addr).unwrap();
+
+        // ipv6 is asked first and doesn't answer
+        let mut buf = [0u8; 512];
+        v6.recv_from(&mut buf).unwrap();
+
+        let (size, from) = v4.recv_from(&mut buf).unwrap();
+        let answer = Message::new(&buf[..size]).unwrap().response(&[]).to_bytes().unwrap();
+        v4.send_to(&answer, &from).unwrap();
+
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        let msg = Message::new(&buf[..size]).unwrap();
+        assert!(msg.is_response());
+        assert_eq!(msg.tx_id, Message::new(include_bytes!("../test/dns_request.bin")).unwrap().tx_id);
+
+        sender.send(ServerEvent::Quit).unwrap();
+        thr.join().unwrap();
+    }
 }
@@ -0,0 +1,1046 @@
+//! The presentation format of RFC 1035 section 5, the one dig prints and
+//! zone files are written in.
+use dns::*;
+use std::fmt;
+use std::str::FromStr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use time;
+
+#[derive (Debug, PartialEq)]
+pub struct ParseError(pub String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn error<T>(msg: String) -> Result<T, ParseError> {
+    Err(ParseError(msg))
+}
+
+const BASE64: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const BASE32HEX: &'static [u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Packs `b` into `bits` wide digits of `alphabet`, the last one padded
+/// with zeroes.
+fn encode_bits(b: &[u8], bits: u32, alphabet: &[u8]) -> String {
+    let mut s = String::new();
+    let mut acc = 0u32;
+    let mut n = 0;
+
+    for c in b.iter() {
+        acc = ((acc << 8) | *c as u32) & 0xffff;
+        n += 8;
+        while n >= bits {
+            n -= bits;
+            s.push(alphabet[((acc >> n) & ((1 << bits) - 1)) as usize] as char);
+        }
+    }
+    if n > 0 {
+        s.push(alphabet[((acc << (bits - n)) & ((1 << bits) - 1)) as usize] as char);
+    }
+    s
+}
+
+fn decode_bits(s: &str, bits: u32, alphabet: &[u8]) -> Option<Vec<u8>> {
+    let mut b = Vec::new();
+    let mut acc = 0u32;
+    let mut n = 0;
+
+    for c in s.bytes() {
+        let digit = match alphabet.iter().position(|a| *a == c) {
+            Some(d) => d as u32,
+            None => return None
+        };
+        acc = ((acc << bits) | digit) & 0xffff;
+        n += bits;
+        if n >= 8 {
+            n -= 8;
+            b.push((acc >> n) as u8);
+        }
+    }
+    Some(b)
+}
+
+fn base64(b: &[u8]) -> String {
+    let mut s = encode_bits(b, 6, BASE64);
+    while s.len() % 4 != 0 {
+        s.push('=');
+    }
+    s
+}
+
+fn from_base64(s: &str) -> Option<Vec<u8>> {
+    decode_bits(s.trim_end_matches('='), 6, BASE64)
+}
+
+fn hex(b: &[u8]) -> String {
+    b.iter().map(|c| format!("{:02X}", c)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None
+    }
+    (0..s.len() / 2).map(|i| s.get(i * 2..i * 2 + 2).and_then(|d| u8::from_str_radix(d, 16).ok())).collect()
+}
+
+/// Writes a name with its trailing dot, escaping what would be taken for
+/// syntax.
+fn fmt_name(f: &mut fmt::Formatter, name: &[u8]) -> fmt::Result {
+    for c in name.iter() {
+        try!(match *c {
+            b'"' | b'(' | b')' | b';' | b'\\' | b'@' | b'$' => write!(f, "\\{}", *c as char),
+            0x21...0x7e => write!(f, "{}", *c as char),
+            _ => write!(f, "\\{:03}", c)
+        });
+    }
+    write!(f, ".")
+}
+
+fn fmt_string(f: &mut fmt::Formatter, s: &[u8]) -> fmt::Result {
+    try!(write!(f, "\""));
+    for c in s.iter() {
+        try!(match *c {
+            b'"' | b'\\' => write!(f, "\\{}", *c as char),
+            0x20...0x7e => write!(f, "{}", *c as char),
+            _ => write!(f, "\\{:03}", c)
+        });
+    }
+    write!(f, "\"")
+}
+
+fn fmt_time(f: &mut fmt::Formatter, t: u32) -> fmt::Result {
+    match time::at_utc(time::Timespec::new(t as i64, 0)).strftime("%Y%m%d%H%M%S") {
+        Ok(s) => write!(f, "{}", s),
+        Err(_) => write!(f, "{}", t)
+    }
+}
+
+/// The types in an NSEC type bitmap (RFC 4034 section 4.1.2).
+fn bitmap_types(b: &[u8]) -> Vec<u16> {
+    let mut types = Vec::new();
+    let mut i = 0;
+
+    while i + 2 <= b.len() {
+        let window = b[i] as u16;
+        let len = b[i + 1] as usize;
+        let end = ::std::cmp::min(i + 2 + len, b.len());
+
+        for (j, bits) in b[i + 2..end].iter().enumerate() {
+            for bit in 0..8 {
+                if bits & (0x80 >> bit) != 0 {
+                    types.push((window << 8) | (j as u16 * 8 + bit));
+                }
+            }
+        }
+        i = end;
+    }
+    types
+}
+
+fn types_bitmap(types: &mut Vec<u16>) -> Vec<u8> {
+    let mut b = Vec::new();
+    types.sort();
+    types.dedup();
+
+    for window in 0..256u16 {
+        let bits: Vec<u16> = types.iter().filter(|t| *t >> 8 == window).map(|t| t & 0xff).collect();
+        if let Some(last) = bits.last() {
+            let mut map = vec![0u8; (*last as usize) / 8 + 1];
+            for t in bits.iter() {
+                map[(*t as usize) / 8] |= 0x80 >> (t % 8);
+            }
+            b.push(window as u8);
+            b.push(map.len() as u8);
+            b.extend(map.into_iter());
+        }
+    }
+    b
+}
+
+impl fmt::Display for QuestionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (*self, self.mnemonic()) {
+            (QuestionType::ALL, _) => write!(f, "ANY"),
+            (_, Some(m)) => write!(f, "{}", m),
+            (_, None) => write!(f, "TYPE{}", self.code())
+        }
+    }
+}
+
+impl fmt::Display for QuestionClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mnemonic() {
+            Some(m) => write!(f, "{}", m),
+            None => write!(f, "CLASS{}", self.code())
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mnemonic() {
+            Some(m) => write!(f, "{}", m.to_uppercase()),
+            None => write!(f, "{}", self.code())
+        }
+    }
+}
+
+impl fmt::Display for Rcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mnemonic() {
+            Some(m) => write!(f, "{}", m.to_uppercase()),
+            None => write!(f, "RCODE{}", self.code())
+        }
+    }
+}
+
+impl fmt::Display for ResourceData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use dns::ResourceData::*;
+        match *self {
+            A(ref addr) => write!(f, "{}", addr),
+            AAAA(ref addr) => write!(f, "{}", addr),
+            NS(ref name) | CNAME(ref name) | PTR(ref name) => fmt_name(f, name),
+            MX { preference, ref exchange } => {
+                try!(write!(f, "{} ", preference));
+                fmt_name(f, exchange)
+            },
+            SOA { ref mname, ref rname, serial, refresh, retry, expire, minimum } => {
+                try!(fmt_name(f, mname));
+                try!(write!(f, " "));
+                try!(fmt_name(f, rname));
+                write!(f, " {} {} {} {} {}", serial, refresh, retry, expire, minimum)
+            },
+            TXT(ref strings) => {
+                for (i, s) in strings.iter().enumerate() {
+                    if i > 0 {
+                        try!(write!(f, " "));
+                    }
+                    try!(fmt_string(f, s));
+                }
+                Ok(())
+            },
+            HINFO { ref cpu, ref os } => {
+                try!(fmt_string(f, cpu));
+                try!(write!(f, " "));
+                fmt_string(f, os)
+            },
+            SRV { priority, weight, port, ref target } => {
+                try!(write!(f, "{} {} {} ", priority, weight, port));
+                fmt_name(f, target)
+            },
+            NAPTR { order, preference, ref flags, ref services, ref regexp, ref replacement } => {
+                try!(write!(f, "{} {} ", order, preference));
+                for s in [flags, services, regexp].iter() {
+                    try!(fmt_string(f, s));
+                    try!(write!(f, " "));
+                }
+                fmt_name(f, replacement)
+            },
+            CAA { flags, ref tag, ref value } => {
+                try!(write!(f, "{} {} ", flags, String::from_utf8_lossy(tag)));
+                fmt_string(f, value)
+            },
+            DS { key_tag, algorithm, digest_type, ref digest } => {
+                write!(f, "{} {} {} {}", key_tag, algorithm, digest_type, hex(digest))
+            },
+            DNSKEY { flags, protocol, algorithm, ref public_key } => {
+                write!(f, "{} {} {} {}", flags, protocol, algorithm, base64(public_key))
+            },
+            RRSIG { type_covered, algorithm, labels, original_ttl, expiration, inception,
+                    key_tag, ref signer_name, ref signature } => {
+                try!(write!(f, "{} {} {} {} ", QuestionType::new(type_covered), algorithm, labels, original_ttl));
+                try!(fmt_time(f, expiration));
+                try!(write!(f, " "));
+                try!(fmt_time(f, inception));
+                try!(write!(f, " {} ", key_tag));
+                try!(fmt_name(f, signer_name));
+                write!(f, " {}", base64(signature))
+            },
+            NSEC { ref next_domain, ref type_bitmaps } => {
+                try!(fmt_name(f, next_domain));
+                for t in bitmap_types(type_bitmaps).iter() {
+                    try!(write!(f, " {}", QuestionType::new(*t)));
+                }
+                Ok(())
+            },
+            NSEC3 { hash_algorithm, flags, iterations, ref salt, ref next_hashed_owner, ref type_bitmaps } => {
+                try!(write!(f, "{} {} {} ", hash_algorithm, flags, iterations));
+                if salt.is_empty() {
+                    try!(write!(f, "-"));
+                } else {
+                    try!(write!(f, "{}", hex(salt)));
+                }
+                try!(write!(f, " {}", encode_bits(next_hashed_owner, 5, BASE32HEX)));
+                for t in bitmap_types(type_bitmaps).iter() {
+                    try!(write!(f, " {}", QuestionType::new(*t)));
+                }
+                Ok(())
+            },
+            // RFC 3597 section 5
+            Bytes(ref b) if b.is_empty() => write!(f, "\\# 0"),
+            Bytes(ref b) => write!(f, "\\# {} {}", b.len(), hex(b))
+        }
+    }
+}
+
+impl fmt::Display for ResourceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(fmt_name(f, &self.r_name));
+        write!(f, "\t{}\t{}\t{}\t{}", self.r_ttl, QuestionClass::new(self.r_class),
+               QuestionType::new(self.r_type), self.r_data)
+    }
+}
+
+impl fmt::Display for Question {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(fmt_name(f, self.raw_name()));
+        write!(f, "\t{}\t{}", self.q_class(), self.q_type())
+    }
+}
+
+/// Laid out like dig's output.
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, ";; ->>HEADER<<- opcode: {}, status: {}, id: {}", self.opcode(), self.rcode(), self.tx_id));
+
+        let flags = [(self.is_response(), "qr"), (self.is_auth_answer(), "aa"), (self.is_truncated(), "tc"),
+                     (self.recursion_desired(), "rd"), (self.recursion_available(), "ra"),
+                     (self.authentic_data(), "ad"), (self.checking_disabled(), "cd")];
+        try!(write!(f, ";; flags:"));
+        for &(_, name) in flags.iter().filter(|&&(on, _)| on) {
+            try!(write!(f, " {}", name));
+        }
+        try!(writeln!(f, "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+                      self.questions().len(), self.answers().len(), self.authority().len(),
+                      self.additional().len() + self.edns.is_some() as usize));
+
+        if let Some(ref edns) = self.edns {
+            try!(writeln!(f, "\n;; OPT PSEUDOSECTION:"));
+            try!(writeln!(f, "; EDNS: version: {}, flags:{}; udp: {}", edns.version,
+                          if edns.dnssec_ok { " do" } else { "" }, edns.payload_size));
+        }
+
+        if !self.questions().is_empty() {
+            try!(writeln!(f, "\n;; QUESTION SECTION:"));
+            for q in self.questions().iter() {
+                try!(writeln!(f, ";{}", q));
+            }
+        }
+
+        for &(name, records) in [("ANSWER", self.answers()), ("AUTHORITY", self.authority()),
+                                 ("ADDITIONAL", self.additional())].iter() {
+            if !records.is_empty() {
+                try!(writeln!(f, "\n;; {} SECTION:", name));
+                for r in records.iter() {
+                    try!(writeln!(f, "{}", r));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive (Debug)]
+struct Token {
+    text: String,
+    quoted: bool
+}
+
+/// A record's worth of tokens, parentheses let it span several lines.
+#[derive (Debug)]
+struct Line {
+    /// starts with white space, the owner is the previous record's
+    same_owner: bool,
+    tokens: Vec<Token>
+}
+
+fn tokenize(s: &str) -> Result<Vec<Line>, ParseError> {
+    let mut lines = Vec::new();
+    let mut line = Line { same_owner: false, tokens: vec![] };
+    let mut chars = s.chars().peekable();
+    let mut depth = 0;
+    let mut at_start = true;
+
+    while let Some(c) = chars.next() {
+        if at_start {
+            line.same_owner = c == ' ' || c == '\t';
+            at_start = false;
+        }
+
+        match c {
+            '\n' if depth == 0 => {
+                if !line.tokens.is_empty() {
+                    lines.push(line);
+                }
+                line = Line { same_owner: false, tokens: vec![] };
+                at_start = true;
+            },
+            ' ' | '\t' | '\r' | '\n' => (),
+            ';' => {
+                while chars.peek().map_or(false, |c| *c != '\n') {
+                    chars.next();
+                }
+            },
+            '(' => depth += 1,
+            ')' if depth == 0 => return error("unbalanced ')'".to_owned()),
+            ')' => depth -= 1,
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            text.push('\\');
+                            match chars.next() {
+                                Some(c) => text.push(c),
+                                None => return error("unterminated string".to_owned())
+                            }
+                        },
+                        Some(c) => text.push(c),
+                        None => return error("unterminated string".to_owned())
+                    }
+                }
+                line.tokens.push(Token { text: text, quoted: true });
+            },
+            _ => {
+                let mut text = String::new();
+                text.push(c);
+                let mut escaped = c == '\\';
+                while let Some(&c) = chars.peek() {
+                    if !escaped && (c.is_whitespace() || c == ';' || c == '(' || c == ')' || c == '"') {
+                        break
+                    }
+                    escaped = !escaped && c == '\\';
+                    text.push(c);
+                    chars.next();
+                }
+                line.tokens.push(Token { text: text, quoted: false });
+            }
+        }
+    }
+
+    if depth != 0 {
+        return error("unbalanced '('".to_owned())
+    }
+    if !line.tokens.is_empty() {
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// Resolves `\X` and `\DDD` escapes.
+fn unescape(s: &str) -> Result<Vec<u8>, ParseError> {
+    let mut b = Vec::new();
+    let bytes = s.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            b.push(bytes[i]);
+            i += 1;
+        } else if i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|c| (*c as char).is_digit(10)) {
+            let n = (bytes[i + 1] - b'0') as u32 * 100 + (bytes[i + 2] - b'0') as u32 * 10 + (bytes[i + 3] - b'0') as u32;
+            if n > 255 {
+                return error(format!("bad escape in {}", s))
+            }
+            b.push(n as u8);
+            i += 4;
+        } else if i + 1 < bytes.len() {
+            b.push(bytes[i + 1]);
+            i += 2;
+        } else {
+            return error(format!("dangling escape in {}", s))
+        }
+    }
+    Ok(b)
+}
+
+/// Parses a name, relative ones are completed with `origin`.
+pub fn parse_name(s: &str, origin: &Name) -> Result<Name, ParseError> {
+    if s == "@" {
+        return Ok(origin.clone())
+    }
+
+    let mut labels = Vec::new();
+    let mut label = String::new();
+    let mut absolute = false;
+    let mut escaped = false;
+
+    for c in s.chars() {
+        absolute = false;
+        if c == '.' && !escaped {
+            labels.push(label);
+            label = String::new();
+            absolute = true;
+        } else {
+            escaped = !escaped && c == '\\';
+            label.push(c);
+        }
+    }
+    if !absolute {
+        labels.push(label);
+    }
+
+    let mut name = Vec::new();
+    if s != "." {
+        for label in labels.iter() {
+            let label = try!(unescape(label));
+            if label.is_empty() || label.len() > LABEL_MAX_LENGTH || label.contains(&b'.') {
+                return error(format!("bad label in {}", s))
+            }
+            if !name.is_empty() {
+                name.push(b'.');
+            }
+            name.extend(label.into_iter());
+        }
+    }
+
+    if !absolute && !origin.is_empty() {
+        name.push(b'.');
+        name.extend(origin.iter().cloned());
+    }
+
+    if name.len() > NAMES_MAX_LENGTH - 2 {
+        return error(format!("name too long: {}", s))
+    }
+    Ok(name.into_iter().collect())
+}
+
+fn parse_type(s: &str) -> Option<u16> {
+    if s.eq_ignore_ascii_case("ANY") {
+        return Some(QuestionType::ALL.code())
+    }
+    // compared as bytes, slicing the str could split a character
+    if s.len() > 4 && s.as_bytes()[..4].eq_ignore_ascii_case(b"TYPE") {
+        return s[4..].parse().ok()
+    }
+    QuestionType::from_mnemonic(s).map(|t| t.code())
+}
+
+fn parse_class(s: &str) -> Option<u16> {
+    if s.len() > 5 && s.as_bytes()[..5].eq_ignore_ascii_case(b"CLASS") {
+        return s[5..].parse().ok()
+    }
+    QuestionClass::from_mnemonic(s).map(|c| c.code())
+}
+
+/// A TTL in seconds, or with BIND's units as in `1h30m`.
+fn parse_ttl(s: &str) -> Result<i32, ParseError> {
+    let mut ttl: i64 = 0;
+    let mut n: i64 = 0;
+    let mut digits = false;
+
+    for c in s.chars() {
+        if let Some(d) = c.to_digit(10) {
+            n = n * 10 + d as i64;
+            digits = true;
+        } else {
+            let unit = match c.to_ascii_lowercase() {
+                's' => 1,
+                'm' => 60,
+                'h' => 3600,
+                'd' => 86400,
+                'w' => 604800,
+                _ => return error(format!("bad TTL: {}", s))
+            };
+            if !digits {
+                return error(format!("bad TTL: {}", s))
+            }
+            ttl += n * unit;
+            n = 0;
+            digits = false;
+        }
+        if ttl + n > i32::max_value() as i64 {
+            return error(format!("TTL too large: {}", s))
+        }
+    }
+    Ok((ttl + n) as i32)
+}
+
+struct Tokens<'a> {
+    tokens: &'a [Token],
+    origin: &'a Name
+}
+
+impl<'a> Tokens<'a> {
+    fn next(&mut self) -> Result<&'a Token, ParseError> {
+        match self.tokens.split_first() {
+            Some((t, rest)) => {
+                self.tokens = rest;
+                Ok(t)
+            },
+            None => error("missing RDATA field".to_owned())
+        }
+    }
+
+    fn word(&mut self) -> Result<&'a str, ParseError> {
+        self.next().map(|t| &t.text[..])
+    }
+
+    fn number<T: FromStr>(&mut self) -> Result<T, ParseError> {
+        let w = try!(self.word());
+        w.parse().or_else(|_| error(format!("bad number: {}", w)))
+    }
+
+    fn ttl(&mut self) -> Result<u32, ParseError> {
+        parse_ttl(try!(self.word())).map(|ttl| ttl as u32)
+    }
+
+    fn name(&mut self) -> Result<Name, ParseError> {
+        let w = try!(self.word());
+        parse_name(w, self.origin)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, ParseError> {
+        let s = try!(unescape(try!(self.word())));
+        if s.len() > 0xff {
+            return error("string longer than 255 bytes".to_owned())
+        }
+        Ok(s)
+    }
+
+    fn time(&mut self) -> Result<u32, ParseError> {
+        let w = try!(self.word());
+        if w.len() == 14 {
+            match time::strptime(w, "%Y%m%d%H%M%S") {
+                Ok(tm) => Ok(tm.to_timespec().sec as u32),
+                Err(_) => error(format!("bad time: {}", w))
+            }
+        } else {
+            w.parse().or_else(|_| error(format!("bad time: {}", w)))
+        }
+    }
+
+    /// What's left, joined up, for the base64 and hex fields that may be
+    /// split by white space.
+    fn rest(&mut self) -> String {
+        let s = self.tokens.iter().map(|t| &t.text[..]).collect();
+        self.tokens = &[];
+        s
+    }
+
+    fn rest_hex(&mut self) -> Result<Vec<u8>, ParseError> {
+        let s = self.rest();
+        from_hex(&s).ok_or(ParseError(format!("bad hex: {}", s)))
+    }
+
+    fn rest_base64(&mut self) -> Result<Vec<u8>, ParseError> {
+        let s = self.rest();
+        from_base64(&s).ok_or(ParseError(format!("bad base64: {}", s)))
+    }
+
+    fn rest_types(&mut self) -> Result<Vec<u8>, ParseError> {
+        let mut types = Vec::new();
+        while !self.tokens.is_empty() {
+            let w = try!(self.word());
+            types.push(try!(parse_type(w).ok_or(ParseError(format!("bad type: {}", w)))));
+        }
+        Ok(types_bitmap(&mut types))
+    }
+
+    fn done(&self) -> Result<(), ParseError> {
+        match self.tokens.first() {
+            Some(t) => error(format!("trailing RDATA: {}", t.text)),
+            None => Ok(())
+        }
+    }
+}
+
+fn parse_rdata(r_type: u16, t: &mut Tokens) -> Result<ResourceData, ParseError> {
+    // the generic form of RFC 3597 works for every type
+    if t.tokens.first().map_or(false, |t| !t.quoted && t.text == "\\#") {
+        try!(t.next());
+        let len: usize = try!(t.number());
+        let b = try!(t.rest_hex());
+        if b.len() != len {
+            return error(format!("RDATA is {} bytes, not {}", b.len(), len))
+        }
+        return ResourceData::decode(r_type, &b).or_else(|_| error("bad RDATA".to_owned()))
+    }
+
+    let data = match r_type {
+        0x01 => {
+            let w = try!(t.word());
+            ResourceData::A(try!(w.parse::<Ipv4Addr>().or_else(|_| error(format!("bad address: {}", w)))))
+        },
+        0x1c => {
+            let w = try!(t.word());
+            ResourceData::AAAA(try!(w.parse::<Ipv6Addr>().or_else(|_| error(format!("bad address: {}", w)))))
+        },
+        0x02 => ResourceData::NS(try!(t.name())),
+        0x05 => ResourceData::CNAME(try!(t.name())),
+        0x0c => ResourceData::PTR(try!(t.name())),
+        0x0f => ResourceData::MX {
+            preference: try!(t.number()),
+            exchange: try!(t.name())
+        },
+        0x06 => ResourceData::SOA {
+            mname: try!(t.name()),
+            rname: try!(t.name()),
+            serial: try!(t.number()),
+            refresh: try!(t.ttl()),
+            retry: try!(t.ttl()),
+            expire: try!(t.ttl()),
+            minimum: try!(t.ttl())
+        },
+        0x10 => {
+            let mut strings = vec![try!(t.string())];
+            while !t.tokens.is_empty() {
+                strings.push(try!(t.string()));
+            }
+            ResourceData::TXT(strings)
+        },
+        0x0d => ResourceData::HINFO {
+            cpu: try!(t.string()),
+            os: try!(t.string())
+        },
+        0x21 => ResourceData::SRV {
+            priority: try!(t.number()),
+            weight: try!(t.number()),
+            port: try!(t.number()),
+            target: try!(t.name())
+        },
+        0x23 => ResourceData::NAPTR {
+            order: try!(t.number()),
+            preference: try!(t.number()),
+            flags: try!(t.string()),
+            services: try!(t.string()),
+            regexp: try!(t.string()),
+            replacement: try!(t.name())
+        },
+        0x101 => ResourceData::CAA {
+            flags: try!(t.number()),
+            tag: try!(t.word()).bytes().collect(),
+            value: try!(unescape(try!(t.word())))
+        },
+        0x2b => ResourceData::DS {
+            key_tag: try!(t.number()),
+            algorithm: try!(t.number()),
+            digest_type: try!(t.number()),
+            digest: try!(t.rest_hex())
+        },
+        0x30 => ResourceData::DNSKEY {
+            flags: try!(t.number()),
+            protocol: try!(t.number()),
+            algorithm: try!(t.number()),
+            public_key: try!(t.rest_base64())
+        },
+        0x2e => ResourceData::RRSIG {
+            type_covered: {
+                let w = try!(t.word());
+                try!(parse_type(w).ok_or(ParseError(format!("bad type: {}", w))))
+            },
+            algorithm: try!(t.number()),
+            labels: try!(t.number()),
+            original_ttl: try!(t.number()),
+            expiration: try!(t.time()),
+            inception: try!(t.time()),
+            key_tag: try!(t.number()),
+            signer_name: try!(t.name()),
+            signature: try!(t.rest_base64())
+        },
+        0x2f => ResourceData::NSEC {
+            next_domain: try!(t.name()),
+            type_bitmaps: try!(t.rest_types())
+        },
+        0x32 => ResourceData::NSEC3 {
+            hash_algorithm: try!(t.number()),
+            flags: try!(t.number()),
+            iterations: try!(t.number()),
+            salt: {
+                let w = try!(t.word());
+                if w == "-" {
+                    vec![]
+                } else {
+                    try!(from_hex(w).ok_or(ParseError(format!("bad salt: {}", w))))
+                }
+            },
+            next_hashed_owner: {
+                let w = try!(t.word());
+                try!(decode_bits(&w.to_uppercase(), 5, BASE32HEX).ok_or(ParseError(format!("bad hash: {}", w))))
+            },
+            type_bitmaps: try!(t.rest_types())
+        },
+        _ => return error(format!("TYPE{} needs the \\# form", r_type))
+    };
+
+    try!(t.done());
+    Ok(data)
+}
+
+/// Parses records in zone file syntax: one per line, or spread over
+/// several with parentheses, `;` comments, `@` for the origin, and the
+/// `$ORIGIN` and `$TTL` directives. The TTL and class may be left out,
+/// as may the owner by starting the line with white space.
+pub fn parse_records(s: &str) -> Result<Vec<ResourceRecord>, ParseError> {
+    let mut records = Vec::new();
+    let mut origin = Name::new();
+    let mut default_ttl = None;
+    let mut last: Option<(Name, i32, u16)> = None;
+
+    for line in try!(tokenize(s)).iter() {
+        let mut tokens = &line.tokens[..];
+
+        if !line.same_owner && tokens[0].text.starts_with('$') {
+            match (&tokens[0].text.to_uppercase()[..], tokens.get(1)) {
+                ("$ORIGIN", Some(t)) => origin = try!(parse_name(&t.text, &Name::new())),
+                ("$TTL", Some(t)) => default_ttl = Some(try!(parse_ttl(&t.text))),
+                _ => return error(format!("unknown directive: {}", tokens[0].text))
+            }
+            continue
+        }
+
+        let owner = if line.same_owner {
+            match last {
+                Some((ref name, _, _)) => name.clone(),
+                None => return error("no previous owner".to_owned())
+            }
+        } else {
+            let name = try!(parse_name(&tokens[0].text, &origin));
+            tokens = &tokens[1..];
+            name
+        };
+
+        let mut ttl = None;
+        let mut class = None;
+        for _ in 0..2 {
+            match tokens.first() {
+                Some(t) if ttl.is_none() && t.text.starts_with(|c: char| c.is_digit(10)) => {
+                    ttl = Some(try!(parse_ttl(&t.text)));
+                },
+                Some(t) if class.is_none() && parse_class(&t.text).is_some() => {
+                    class = parse_class(&t.text);
+                },
+                _ => break
+            }
+            tokens = &tokens[1..];
+        }
+
+        let r_type = match tokens.first() {
+            Some(t) => try!(parse_type(&t.text).ok_or(ParseError(format!("bad type: {}", t.text)))),
+            None => return error("missing type".to_owned())
+        };
+
+        let ttl = match ttl.or(default_ttl).or(last.as_ref().map(|l| l.1)) {
+            Some(ttl) => ttl,
+            None => return error("no TTL".to_owned())
+        };
+        let class = class.or(last.as_ref().map(|l| l.2)).unwrap_or(QuestionClass::IN.code());
+
+        let data = try!(parse_rdata(r_type, &mut Tokens { tokens: &tokens[1..], origin: &origin }));
+
+        last = Some((owner.clone(), ttl, class));
+        records.push(ResourceRecord {
+            r_name: owner,
+            r_type: r_type,
+            r_class: class,
+            r_ttl: ttl,
+            r_data: data
+        });
+    }
+
+    Ok(records)
+}
+
+impl FromStr for ResourceRecord {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<ResourceRecord, ParseError> {
+        let mut records = try!(parse_records(s));
+        match records.len() {
+            1 => Ok(records.pop().unwrap()),
+            n => error(format!("{} records, not one", n))
+        }
+    }
+}
+
+/// `name [class] type`, the class defaults to IN.
+impl FromStr for Question {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Question, ParseError> {
+        let words: Vec<&str> = s.trim_start_matches(';').split_whitespace().collect();
+        let (name, class, t) = match words.len() {
+            2 => (words[0], "IN", words[1]),
+            3 => (words[0], words[1], words[2]),
+            _ => return error(format!("bad question: {}", s))
+        };
+
+        let q_class = try!(parse_class(class).ok_or(ParseError(format!("bad class: {}", class))));
+        let q_type = try!(parse_type(t).ok_or(ParseError(format!("bad type: {}", t))));
+
+        Ok(Question::new(try!(parse_name(name, &Name::new())), QuestionType::new(q_type), QuestionClass::new(q_class)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::super::*;
+    use dns::*;
+
+    fn name(s: &str) -> Name {
+        s.bytes().collect()
+    }
+
+    #[test]
+    fn display() {
+        let msg = Message::new(include_bytes!("../test/referral_response.bin")).unwrap();
+
+        assert_eq!("example.com.\tIN\tA", msg.questions()[0].to_string());
+        assert_eq!("example.com.\t86400\tIN\tNS\ta.iana-servers.net.", msg.authority()[0].to_string());
+        assert_eq!(";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 7982\n\
+                    ;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 2, ADDITIONAL: 2\n\
+                    \n\
+                    ;; QUESTION SECTION:\n\
+                    ;example.com.\tIN\tA\n\
+                    \n\
+                    ;; ANSWER SECTION:\n\
+                    example.com.\t3600\tIN\tA\t93.184.216.34\n\
+                    \n\
+                    ;; AUTHORITY SECTION:\n\
+                    example.com.\t86400\tIN\tNS\ta.iana-servers.net.\n\
+                    example.com.\t86400\tIN\tNS\tb.iana-servers.net.\n\
+                    \n\
+                    ;; ADDITIONAL SECTION:\n\
+                    a.iana-servers.net.\t172800\tIN\tA\t199.43.135.53\n\
+                    b.iana-servers.net.\t172800\tIN\tA\t199.43.133.53\n", msg.to_string());
+
+        let mut query = Message::new(include_bytes!("../test/dns_request.bin")).unwrap();
+        query.edns = Some(Edns { dnssec_ok: true, .. Edns::new(1232) });
+        assert!(query.to_string().contains("; EDNS: version: 0, flags: do; udp: 1232\n"));
+
+        assert_eq!("TYPE65280", QuestionType::new(0xff00).to_string());
+        assert_eq!("ANY", QuestionType::ALL.to_string());
+        assert_eq!("CLASS7", QuestionClass::new(7).to_string());
+        assert_eq!("NXDOMAIN", Rcode::NXDomain.to_string());
+    }
+
+    #[test]
+    fn round_trip() {
+        let lines = [
+            "example.com.\t300\tIN\tA\t192.0.2.1",
+            "example.com.\t300\tIN\tAAAA\t2001:db8::1",
+            "example.com.\t300\tIN\tNS\tns1.example.com.",
+            "www.example.com.\t300\tIN\tCNAME\texample.com.",
+            "1.2.0.192.in-addr.arpa.\t300\tIN\tPTR\texample.com.",
+            "example.com.\t300\tIN\tMX\t10 mail.example.com.",
+            "example.com.\t300\tIN\tSOA\tns1.example.com. hostmaster.example.com. 2016010101 7200 3600 1209600 300",
+            "example.com.\t300\tIN\tTXT\t\"v=spf1 -all\" \"a \\\"quoted\\\" \\\\ \\009\"",
+            "example.com.\t300\tIN\tHINFO\t\"x86\" \"linux\"",
+            "_sip._udp.example.com.\t300\tIN\tSRV\t1 2 5060 sip.example.com.",
+            "example.com.\t300\tIN\tNAPTR\t100 10 \"u\" \"E2U+sip\" \"!^.*$!sip:info@example.com!\" .",
+            "example.com.\t300\tIN\tCAA\t0 issue \"ca.example.net\"",
+            "example.com.\t300\tIN\tDS\t2371 13 2 1F987CC6583E92DF0890718C42",
+            "example.com.\t300\tIN\tDNSKEY\t257 3 13 mdsswUyr3DPW132mOi8V9xESWE8jTo0dxCjjnopKl+GqJxpVXckHAeF+KkxLbxILfDLUT0rAK9iUzy1L53eKGQ==",
+            "example.com.\t300\tIN\tRRSIG\tA 13 2 300 20161231235959 20161201000000 2371 example.com. oJB1W6WNGv+ldvQ3WDG0MQkg5IEhjRip8WTrPYGv07h108dUKGMeDPKijVCHX3DDKdfb+v6oB9wfuh3DTJXUAfI=",
+            "example.com.\t300\tIN\tNSEC\ta.example.com. A NS SOA MX RRSIG NSEC DNSKEY CAA",
+            "example.com.\t300\tIN\tNSEC3\t1 0 10 AABB 2T7B4G4VSA5SMI47K61MV5BV1A22BOJR A RRSIG",
+            "example.com.\t300\tIN\tNSEC3\t1 1 0 - 2T7B4G4VSA5SMI47K61MV5BV1A22BOJR NS",
+            "example.com.\t300\tIN\tTYPE99\t\\# 3 010203",
+            "example.com.\t300\tCH\tTYPE99\t\\# 0",
+            "\\$x\\\"y\\@z\\010.example.com.\t300\tIN\tA\t192.0.2.1"
+        ];
+
+        for line in lines.iter() {
+            let r: ResourceRecord = line.parse().unwrap();
+            assert_eq!(*line, r.to_string());
+
+            // and the wire format agrees
+            let mut b = Vec::new();
+            r.write(&mut b).unwrap();
+            assert_eq!(r.r_data, ResourceRecord::read(&b).unwrap().0.r_data);
+        }
+    }
+
+    #[test]
+    fn zone() {
+        let zone = "$ORIGIN example.com.\n\
+                    $TTL 1h\n\
+                    @  IN  SOA  ns1 hostmaster (\n\
+                                2016010101 ; serial\n\
+                                2h 3600 1209600 300 )\n\
+                    \n\
+                    ; name servers\n\
+                    \tNS  ns1\n\
+                    \tNS  ns2.example.net.\n\
+                    ns1 300 A 192.0.2.1\n\
+                    www 1d2h CNAME @\n\
+                    txt TXT \"two; words\" bare\n\
+                    other.org. IN 60 AAAA 2001:db8::2\n";
+
+        let records = parse_records(zone).unwrap();
+        assert_eq!(7, records.len());
+
+        assert_eq!(name("example.com"), records[0].r_name);
+        assert_eq!(3600, records[0].r_ttl);
+        match records[0].r_data {
+            ResourceData::SOA { ref mname, ref rname, serial, refresh, .. } => {
+                assert_eq!(name("ns1.example.com"), *mname);
+                assert_eq!(name("hostmaster.example.com"), *rname);
+                assert_eq!(2016010101, serial);
+                assert_eq!(7200, refresh);
+            },
+            ref r => panic!("{:?}", r)
+        }
+
+        // blank owners repeat the previous one
+        assert_eq!(name("example.com"), records[1].r_name);
+        assert_eq!(ResourceData::NS(name("ns1.example.com")), records[1].r_data);
+        assert_eq!(ResourceData::NS(name("ns2.example.net")), records[2].r_data);
+
+        assert_eq!(300, records[3].r_ttl);
+        assert_eq!(93600, records[4].r_ttl);
+        assert_eq!(ResourceData::CNAME(name("example.com")), records[4].r_data);
+        assert_eq!(ResourceData::TXT(vec![b"two; words".to_vec(), b"bare".to_vec()]), records[5].r_data);
+        assert_eq!(name("other.org"), records[6].r_name);
+        assert_eq!(60, records[6].r_ttl);
+
+        // without an origin names are taken as absolute
+        let r: ResourceRecord = "host 60 A 192.0.2.2".parse().unwrap();
+        assert_eq!(name("host"), r.r_name);
+        assert_eq!(1, r.r_class);
+
+        let q: Question = ";example.com. IN AAAA".parse().unwrap();
+        assert_eq!("example.com", q.name());
+        assert_eq!(QuestionType::AAAA, q.q_type());
+        let q: Question = "example.com TYPE65".parse().unwrap();
+        assert_eq!(QuestionType::HTTPS, q.q_type());
+    }
+
+    #[test]
+    fn errors() {
+        for s in [
+            "example.com. A 192.0.2.1",
+            "example.com. 60 A 192.0.2.300",
+            "example.com. 60 A 192.0.2.1 extra",
+            "example.com. 60 BOGUS 1",
+            "example.com. 60 MX mail.example.com.",
+            "example.com. 60 TYPE99 010203",
+            "example.com. 60 TYPE99 \\# 4 010203",
+            "example.com. 60 TXT \"unterminated",
+            "example.com. 60 SOA ( ns1 hostmaster 1 2 3 4 5",
+            "a..b. 60 A 192.0.2.1",
+            "a\\.b. 60 A 192.0.2.1",
+            "$INCLUDE other.zone",
+            "\tA 192.0.2.1",
+            "host 60 abc\u{e9} 1.2.3.4",
+            "host 60 CLAS\u{e9} A 1.2.3.4",
+            "host 60 \u{e9}\u{e9}\u{e9} 1.2.3.4"
+        ].iter() {
+            assert!(s.parse::<ResourceRecord>().is_err(), "{}", s);
+        }
+
+        let mut long = String::new();
+        for _ in 0..4 {
+            long.push_str(&"a".repeat(63));
+            long.push('.');
+        }
+        assert!(parse_name(&long, &Name::new()).is_err());
+        assert!(parse_name(&long[64..], &Name::new()).is_ok());
+        assert!(parse_name(&"a".repeat(64), &Name::new()).is_err());
+    }
+}